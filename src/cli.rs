@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::{Backend, Config, ConfigError};
+
+/// A (very simple) HTTP load balancer
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the load balancer
+    Run(ConfigArgs),
+
+    /// Validate a config and print the resolved settings, without binding any sockets
+    CheckConfig(ConfigArgs),
+
+    /// Probe every backend in the pool once and print whether it is healthy
+    ListBackends(ConfigArgs),
+}
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    /// Path to the TOML config file
+    pub config: PathBuf,
+
+    /// Override the address the load balancer listens on
+    #[arg(long, value_name = "ADDRESS")]
+    pub listen: Option<String>,
+
+    /// Override the backend pool, may be given several times
+    #[arg(long = "backend", value_name = "ADDRESS")]
+    pub backends: Vec<String>,
+
+    /// Override the milliseconds between two rounds of health checks
+    #[arg(long, value_name = "MILLIS")]
+    pub healthcheck_period_millis: Option<u64>,
+
+    /// Override the verbosity level (0-3)
+    #[arg(short, long, value_name = "LEVEL")]
+    pub verbose: Option<u8>,
+
+    /// Reload the backend pool whenever the config file changes
+    #[arg(long)]
+    pub watch_config: bool,
+}
+
+impl ConfigArgs {
+    // read the config file, apply the command line overrides on top, then validate
+    pub fn resolve(&self) -> Result<Config, ConfigError> {
+        let mut config = Config::read(&self.config)?;
+
+        if let Some(listen) = &self.listen {
+            config.listen = listen.clone();
+        }
+
+        if !self.backends.is_empty() {
+            config.backends = self.backends.iter()
+                .map(|address| Backend { address: address.clone(), weight: 1, priority: 0 })
+                .collect();
+        }
+
+        if let Some(healthcheck_period_millis) = self.healthcheck_period_millis {
+            config.healthcheck_period_millis = healthcheck_period_millis;
+        }
+
+        if let Some(verbose) = self.verbose {
+            config.verbose = verbose;
+        }
+
+        if self.watch_config {
+            config.watch_config = true;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
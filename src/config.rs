@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::errors;
+use crate::strategy::{self, HashKey};
+
+const DEFAULT_HEALTHCHECK_PERIOD_MILLIS: u64 = 60 * 1000;
+const DEFAULT_VERBOSE: u8 = 1;
+const MAX_VERBOSE: u8 = 3;
+const DEFAULT_WEIGHT: u32 = 1;
+const MAX_WEIGHT: u32 = 1000;
+const DEFAULT_KEEPALIVE_TIMEOUT_MILLIS: u64 = 5 * 1000;
+const DEFAULT_POOL_MAX_IDLE: usize = 8;
+const DEFAULT_POOL_IDLE_TIMEOUT_MILLIS: u64 = 4 * 1000;
+const DEFAULT_UPSTREAM_TIMEOUT_MILLIS: u64 = 30 * 1000;
+const DEFAULT_RETRY_AFTER_SECS: u64 = 5;
+const DEFAULT_FAILOVER_THRESHOLD: f64 = 0.5;
+const DEFAULT_HASH_KEY: &str = "client_ip";
+const DEFAULT_VIRTUAL_NODES: u32 = 160;
+const MAX_VIRTUAL_NODES: u32 = 1000;
+// points on the consistent hash ring, across all backends. the ring is rebuilt
+// by the first request after a reload, this keeps that under ten milliseconds
+const MAX_RING_POINTS: u64 = 50_000;
+const DEFAULT_TABLE_SIZE: usize = 65537;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // address the load balancer listens on
+    pub listen: String,
+
+    // milliseconds between two rounds of health checks
+    #[serde(default = "default_healthcheck_period_millis")]
+    pub healthcheck_period_millis: u64,
+
+    // verbosity level
+    // 0: none
+    // 1: request line only
+    // 2: request line and headers
+    // 3: request line, headers, and body
+    #[serde(default = "default_verbose")]
+    pub verbose: u8,
+
+    // milliseconds an idle client connection is kept open for its next request,
+    // one request per connection when 0
+    #[serde(default = "default_keepalive_timeout_millis")]
+    pub keepalive_timeout_millis: u64,
+
+    // idle keep-alive connections kept open to every backend, no pooling when 0
+    #[serde(default = "default_pool_max_idle")]
+    pub pool_max_idle: usize,
+
+    // milliseconds a pooled connection may sit idle before it is closed
+    #[serde(default = "default_pool_idle_timeout_millis")]
+    pub pool_idle_timeout_millis: u64,
+
+    // milliseconds to wait on a backend reading or writing before answering the
+    // client with a 504, no timeout when 0
+    #[serde(default = "default_upstream_timeout_millis")]
+    pub upstream_timeout_millis: u64,
+
+    // name of the strategy picking the next backend
+    #[serde(default = "default_strategy")]
+    pub strategy: String,
+
+    // healthy fraction of a priority group under which traffic spills to the
+    // next group
+    #[serde(default = "default_failover_threshold")]
+    pub failover_threshold: f64,
+
+    // milliseconds a backend takes to ramp up to its full weight after turning
+    // healthy, no slow start when 0
+    #[serde(default)]
+    pub slow_start_millis: u64,
+
+    // name of the cookie pinning clients to the backend they were first routed
+    // to, no sticky sessions when unset
+    #[serde(default)]
+    pub sticky_cookie: Option<String>,
+
+    // settings of the consistent_hash strategy
+    #[serde(default)]
+    pub consistent_hash: ConsistentHash,
+
+    // settings of the maglev strategy
+    #[serde(default)]
+    pub maglev: Maglev,
+
+    // settings of the rendezvous strategy
+    #[serde(default)]
+    pub rendezvous: Rendezvous,
+
+    // responses of the load balancer itself
+    #[serde(default)]
+    pub errors: Errors,
+
+    // reload the backend pool when this file changes, on top of SIGHUP
+    #[serde(default)]
+    pub watch_config: bool,
+
+    pub backends: Vec<Backend>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backend {
+    // host:port of the backend
+    pub address: String,
+
+    // share of the traffic relative to the other backends
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+
+    // group of the backend, lower is preferred. higher groups only get traffic
+    // when the healthy fraction of the lower ones drops below failover_threshold
+    #[serde(default)]
+    pub priority: u32,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsistentHash {
+    // what requests are hashed on: client_ip, path, or header:<name>
+    #[serde(default = "default_hash_key")]
+    pub key: String,
+
+    // points on the ring per unit of backend weight
+    #[serde(default = "default_virtual_nodes")]
+    pub virtual_nodes: u32,
+}
+
+impl Default for ConsistentHash {
+    fn default() -> ConsistentHash {
+        ConsistentHash {
+            key: default_hash_key(),
+            virtual_nodes: default_virtual_nodes(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Maglev {
+    // what requests are hashed on: client_ip, path, or header:<name>
+    #[serde(default = "default_hash_key")]
+    pub key: String,
+
+    // entries in the lookup table, a prime much larger than the number of backends
+    #[serde(default = "default_table_size")]
+    pub table_size: usize,
+}
+
+impl Default for Maglev {
+    fn default() -> Maglev {
+        Maglev {
+            key: default_hash_key(),
+            table_size: default_table_size(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rendezvous {
+    // what requests are hashed on: client_ip, path, or header:<name>
+    #[serde(default = "default_hash_key")]
+    pub key: String,
+}
+
+impl Default for Rendezvous {
+    fn default() -> Rendezvous {
+        Rendezvous { key: default_hash_key() }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Errors {
+    // seconds sent in the Retry-After header of 5xx responses, none when 0
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+
+    // bodies replacing the default ones, by status: 400, 502, 503 or 504
+    #[serde(default)]
+    pub bodies: HashMap<String, String>,
+
+    // html and json pages served instead of the body to clients accepting them,
+    // by status
+    #[serde(default)]
+    pub pages: HashMap<String, ErrorPage>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPage {
+    // files relative to the config file
+    pub html: Option<PathBuf>,
+    pub json: Option<PathBuf>,
+}
+
+impl Default for Errors {
+    fn default() -> Errors {
+        Errors {
+            retry_after_secs: default_retry_after_secs(),
+            bodies: HashMap::new(),
+            pages: HashMap::new(),
+        }
+    }
+}
+
+fn default_healthcheck_period_millis() -> u64 {
+    DEFAULT_HEALTHCHECK_PERIOD_MILLIS
+}
+
+fn default_verbose() -> u8 {
+    DEFAULT_VERBOSE
+}
+
+fn default_keepalive_timeout_millis() -> u64 {
+    DEFAULT_KEEPALIVE_TIMEOUT_MILLIS
+}
+
+fn default_pool_max_idle() -> usize {
+    DEFAULT_POOL_MAX_IDLE
+}
+
+fn default_pool_idle_timeout_millis() -> u64 {
+    DEFAULT_POOL_IDLE_TIMEOUT_MILLIS
+}
+
+fn default_upstream_timeout_millis() -> u64 {
+    DEFAULT_UPSTREAM_TIMEOUT_MILLIS
+}
+
+fn default_retry_after_secs() -> u64 {
+    DEFAULT_RETRY_AFTER_SECS
+}
+
+fn default_strategy() -> String {
+    strategy::DEFAULT.to_string()
+}
+
+fn default_failover_threshold() -> f64 {
+    DEFAULT_FAILOVER_THRESHOLD
+}
+
+fn default_weight() -> u32 {
+    DEFAULT_WEIGHT
+}
+
+fn default_hash_key() -> String {
+    DEFAULT_HASH_KEY.to_string()
+}
+
+fn default_virtual_nodes() -> u32 {
+    DEFAULT_VIRTUAL_NODES
+}
+
+fn default_table_size() -> usize {
+    DEFAULT_TABLE_SIZE
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read config {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse config {}: {}", path.display(), e),
+            ConfigError::Invalid { key, message } => write!(f, "invalid config key `{}`: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(key: impl Into<String>, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid { key: key.into(), message: message.into() }
+}
+
+impl Config {
+    // read and parse the config file, without validating the values
+    pub fn read(path: &Path) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+
+        let mut config: Config = toml::from_str(&contents)
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        // error pages are found next to the config file
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for page in config.errors.pages.values_mut() {
+            for file in [&mut page.html, &mut page.json].into_iter().flatten() {
+                *file = dir.join(&*file);
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.parse::<SocketAddr>().is_err() {
+            return Err(invalid("listen", format!("{:?} is not an ip:port address", self.listen)));
+        }
+
+        if self.healthcheck_period_millis == 0 {
+            return Err(invalid("healthcheck_period_millis", "must be greater than 0"));
+        }
+
+        if self.verbose > MAX_VERBOSE {
+            return Err(invalid("verbose", format!("must be between 0 and {}", MAX_VERBOSE)));
+        }
+
+        if !strategy::exists(&self.strategy) {
+            return Err(invalid("strategy", format!(
+                "unknown strategy {:?}, expected one of {}", self.strategy, strategy::names().join(", ")
+            )));
+        }
+
+        if !(0.0..=1.0).contains(&self.failover_threshold) {
+            return Err(invalid("failover_threshold", "must be between 0 and 1"));
+        }
+
+        if let Some(cookie) = &self.sticky_cookie {
+            if cookie.is_empty() || !cookie.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+                return Err(invalid("sticky_cookie", format!("{:?} is not a valid cookie name", cookie)));
+            }
+        }
+
+        if HashKey::parse(&self.consistent_hash.key).is_none() {
+            return Err(invalid("consistent_hash.key", format!(
+                "{:?} is not one of client_ip, path, or header:<name>", self.consistent_hash.key
+            )));
+        }
+
+        if self.consistent_hash.virtual_nodes == 0 || self.consistent_hash.virtual_nodes > MAX_VIRTUAL_NODES {
+            return Err(invalid("consistent_hash.virtual_nodes", format!("must be between 1 and {}", MAX_VIRTUAL_NODES)));
+        }
+
+        if HashKey::parse(&self.maglev.key).is_none() {
+            return Err(invalid("maglev.key", format!(
+                "{:?} is not one of client_ip, path, or header:<name>", self.maglev.key
+            )));
+        }
+
+        if !is_prime(self.maglev.table_size) {
+            return Err(invalid("maglev.table_size", format!("{} is not a prime", self.maglev.table_size)));
+        }
+
+        if HashKey::parse(&self.rendezvous.key).is_none() {
+            return Err(invalid("rendezvous.key", format!(
+                "{:?} is not one of client_ip, path, or header:<name>", self.rendezvous.key
+            )));
+        }
+
+        let statuses = self.errors.bodies.keys().map(|status| ("bodies", status))
+            .chain(self.errors.pages.keys().map(|status| ("pages", status)));
+        for (table, status) in statuses {
+            if !status.parse::<u16>().is_ok_and(|status| errors::STATUSES.contains(&status)) {
+                return Err(invalid(format!("errors.{}.{}", table, status), format!(
+                    "not a status the load balancer answers with, expected one of {}",
+                    errors::STATUSES.map(|status| status.to_string()).join(", ")
+                )));
+            }
+        }
+
+        for (status, page) in &self.errors.pages {
+            for (format, file) in [("html", &page.html), ("json", &page.json)] {
+                if let Some(file) = file {
+                    fs::File::open(file).map_err(|e| invalid(
+                        format!("errors.pages.{}.{}", status, format), format!("could not read {}: {}", file.display(), e)
+                    ))?;
+                }
+            }
+        }
+
+        if self.backends.is_empty() {
+            return Err(invalid("backends", "at least one backend is required"));
+        }
+
+        for (i, backend) in self.backends.iter().enumerate() {
+            let key = format!("backends[{}].address", i);
+            validate_address(&backend.address).map_err(|message| invalid(key.clone(), message))?;
+
+            if backend.weight == 0 || backend.weight > MAX_WEIGHT {
+                return Err(invalid(format!("backends[{}].weight", i), format!("must be between 1 and {}", MAX_WEIGHT)));
+            }
+
+            if self.backends[..i].iter().any(|other| other.address == backend.address) {
+                return Err(invalid(key, format!("duplicate backend {:?}", backend.address)));
+            }
+        }
+
+        if self.strategy == "consistent_hash" {
+            let points: u64 = self.backends.iter()
+                .map(|backend| self.consistent_hash.virtual_nodes as u64 * backend.weight as u64)
+                .sum();
+            if points > MAX_RING_POINTS {
+                return Err(invalid("consistent_hash.virtual_nodes", format!(
+                    "virtual_nodes times the backend weights adds up to {} points on the ring, at most {}",
+                    points, MAX_RING_POINTS
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d))
+}
+
+// backends may be given by hostname, so only check the host:port shape
+fn validate_address(address: &str) -> Result<(), String> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => match port.parse::<u16>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("{:?} has an invalid port", address)),
+        },
+        _ => Err(format!("{:?} is not a host:port address", address)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a valid config with `extra` added at the top and two backends
+    fn parse(extra: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(&format!(
+            "listen = \"127.0.0.1:8000\"\n{}\n\n[[backends]]\naddress = \"10.0.0.1:8080\"\n\n[[backends]]\naddress = \"10.0.0.2:8080\"\n",
+            extra
+        ))
+    }
+
+    // the key and message of the error validating `config`
+    fn rejected(config: &Config) -> (String, String) {
+        match config.validate() {
+            Err(ConfigError::Invalid { key, message }) => (key, message),
+            other => panic!("expected an invalid key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        let config = parse("").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.strategy, strategy::DEFAULT);
+        assert_eq!(config.backends[0].weight, DEFAULT_WEIGHT);
+    }
+
+    #[test]
+    fn rejects_a_bad_listen_address() {
+        let mut config = parse("").unwrap();
+        for listen in ["localhost:8000", "127.0.0.1", "127.0.0.1:99999"] {
+            config.listen = listen.to_string();
+            assert_eq!(rejected(&config).0, "listen");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(parse("listen_port = 8000").is_err());
+        assert!(parse("[maglev]\ntable_size = 7\nsize = 7").is_err());
+
+        let error = toml::from_str::<Config>(
+            "listen = \"127.0.0.1:8000\"\n[[backends]]\naddress = \"10.0.0.1:8080\"\nwieght = 2\n"
+        ).unwrap_err();
+        assert!(error.to_string().contains("wieght"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_weights_out_of_range() {
+        let mut config = parse("").unwrap();
+        for weight in [0, MAX_WEIGHT + 1] {
+            config.backends[1].weight = weight;
+            let (key, message) = rejected(&config);
+            assert_eq!(key, "backends[1].weight");
+            assert_eq!(message, "must be between 1 and 1000");
+        }
+
+        config.backends[1].weight = MAX_WEIGHT;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn bounds_the_size_of_the_ring() {
+        let mut config = parse("strategy = \"consistent_hash\"\n[consistent_hash]\nvirtual_nodes = 250").unwrap();
+        config.backends[0].weight = 100;
+        config.backends[1].weight = 100;
+        assert!(config.validate().is_ok());
+
+        config.backends[1].weight = 101;
+        assert_eq!(rejected(&config).0, "consistent_hash.virtual_nodes");
+
+        // only the consistent_hash strategy builds a ring
+        config.strategy = "round_robin".to_string();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_backends() {
+        let mut config = parse("").unwrap();
+        config.backends[1].address = config.backends[0].address.clone();
+
+        let (key, message) = rejected(&config);
+        assert_eq!(key, "backends[1].address");
+        assert!(message.contains("duplicate"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_a_table_size_that_is_not_prime() {
+        for table_size in [0, 1, 65536, 65535] {
+            let config = parse(&format!("[maglev]\ntable_size = {}", table_size)).unwrap();
+            assert_eq!(rejected(&config).0, "maglev.table_size");
+        }
+
+        assert!(parse("[maglev]\ntable_size = 251").unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_hash_keys() {
+        for table in ["consistent_hash", "maglev", "rendezvous"] {
+            for key in ["host", "header:", ""] {
+                let config = parse(&format!("[{}]\nkey = {:?}", table, key)).unwrap();
+                assert_eq!(rejected(&config).0, format!("{}.key", table));
+            }
+
+            let config = parse(&format!("[{}]\nkey = \"header:X-Tenant\"", table)).unwrap();
+            assert!(config.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_statuses_the_balancer_never_sends() {
+        let config = parse("[errors.pages.404]\nhtml = \"404.html\"").unwrap();
+        assert_eq!(rejected(&config).0, "errors.pages.404");
+
+        let config = parse("[errors.bodies]\n200 = \"ok\"").unwrap();
+        assert_eq!(rejected(&config).0, "errors.bodies.200");
+
+        let config = parse("[errors.bodies]\n503 = \"down for maintenance\"").unwrap();
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::config;
+use crate::now;
+
+// statuses the load balancer answers with itself
+pub const STATUSES: [u16; 4] = [400, 502, 503, 504];
+
+// responses of the load balancer itself, for requests it cannot proxy
+pub struct ErrorResponses {
+    // seconds clients are asked to wait before retrying after a 5xx, none when 0
+    retry_after_secs: u64,
+    // bodies replacing the default ones, by status
+    bodies: HashMap<u16, String>,
+    // pages served to clients accepting them, by status, in order of preference
+    // when a client accepts several equally
+    pages: HashMap<u16, Vec<Page>>,
+}
+
+struct Page {
+    media_type: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl ErrorResponses {
+    // load the error pages, a page that cannot be read falls back to the
+    // plain text body
+    pub fn new(config: &config::Errors) -> ErrorResponses {
+        let status = |status: &String| -> u16 { status.parse().expect("statuses are validated with the config") };
+
+        let pages = config.pages.iter()
+            .map(|(code, page)| {
+                let files = [
+                    ("application/json", "application/json", &page.json),
+                    ("text/html", "text/html; charset=utf-8", &page.html),
+                ];
+
+                let pages = files.into_iter()
+                    .filter_map(|(media_type, content_type, file)| {
+                        let file = file.as_ref()?;
+                        match fs::read(file) {
+                            Ok(body) => Some(Page { media_type, content_type, body }),
+                            Err(e) => {
+                                println!("{} lb [WARN] could not read error page {}: {}", now(), file.display(), e);
+                                None
+                            }
+                        }
+                    })
+                    .collect();
+
+                (status(code), pages)
+            })
+            .collect();
+
+        ErrorResponses {
+            retry_after_secs: config.retry_after_secs,
+            bodies: config.bodies.iter().map(|(code, body)| (status(code), body.clone())).collect(),
+            pages,
+        }
+    }
+
+    // a complete response with `status` for a client sending `accept`, closing
+    // the connection
+    pub fn response(&self, status: u16, accept: Option<&str>) -> Vec<u8> {
+        let pages = self.pages.get(&status).map(Vec::as_slice).unwrap_or_default();
+
+        // the page the client likes best, plain text when it accepts none of them
+        let mut best: Option<&Page> = None;
+        let mut best_quality = 0.0;
+        for page in pages {
+            let quality = quality(accept.unwrap_or("*/*"), page.media_type);
+            if quality > best_quality {
+                best = Some(page);
+                best_quality = quality;
+            }
+        }
+
+        let (content_type, body) = match (best, self.bodies.get(&status)) {
+            (Some(page), _) => (page.content_type, page.body.clone()),
+            (None, Some(body)) => ("text/plain; charset=utf-8", body.clone().into_bytes()),
+            (None, None) => ("text/plain; charset=utf-8", format!("{} {}\n", status, reason(status)).into_bytes()),
+        };
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+        head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        if !pages.is_empty() {
+            head.push_str("Vary: Accept\r\n");
+        }
+        if status >= 500 && self.retry_after_secs > 0 {
+            head.push_str(&format!("Retry-After: {}\r\n", self.retry_after_secs));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        [head.into_bytes(), body].concat()
+    }
+}
+
+// quality an Accept header gives `media_type`, from the most specific range
+// matching it: the type itself, then type/*, then */*
+fn quality(accept: &str, media_type: &str) -> f32 {
+    let main_type = media_type.split('/').next().unwrap_or("");
+    let mut best: Option<(u8, f32)> = None;
+
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let range = params.next().unwrap_or("").trim();
+
+        let specificity = match range.split_once('/') {
+            _ if range.eq_ignore_ascii_case(media_type) => 2,
+            Some((range_type, "*")) if range_type.eq_ignore_ascii_case(main_type) => 1,
+            Some(("*", "*")) => 0,
+            _ => continue,
+        };
+
+        let quality = params
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(1.0, |(_, value)| value.trim().parse().unwrap_or(0.0));
+
+        if best.is_none_or(|(best_specificity, _)| specificity > best_specificity) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(retry_after_secs: u64, bodies: &[(&str, &str)]) -> ErrorResponses {
+        ErrorResponses::new(&config::Errors {
+            retry_after_secs,
+            bodies: bodies.iter().map(|(status, body)| (status.to_string(), body.to_string())).collect(),
+            pages: HashMap::new(),
+        })
+    }
+
+    #[test]
+    fn answers_with_the_status_by_default() {
+        let response = String::from_utf8(errors(0, &[]).response(502, None)).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(response.contains("Content-Length: 16\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("Retry-After"));
+        assert!(response.ends_with("\r\n\r\n502 Bad Gateway\n"));
+    }
+
+    #[test]
+    fn uses_configured_bodies_and_retry_after() {
+        let errors = errors(30, &[("503", "down for maintenance")]);
+
+        let response = String::from_utf8(errors.response(503, None)).unwrap();
+        assert!(response.contains("Retry-After: 30\r\n"));
+        assert!(response.contains("Content-Length: 20\r\n"));
+        assert!(response.ends_with("\r\n\r\ndown for maintenance"));
+
+        // client errors are not retried
+        let response = String::from_utf8(errors.response(400, None)).unwrap();
+        assert!(!response.contains("Retry-After"));
+    }
+
+    fn with_pages() -> ErrorResponses {
+        let mut errors = errors(0, &[("503", "down")]);
+        errors.pages.insert(503, vec![
+            Page { media_type: "application/json", content_type: "application/json", body: b"{}".to_vec() },
+            Page { media_type: "text/html", content_type: "text/html; charset=utf-8", body: b"<p>down</p>".to_vec() },
+        ]);
+        errors
+    }
+
+    #[test]
+    fn negotiates_pages_on_accept() {
+        let errors = with_pages();
+        let body = |accept: Option<&str>| {
+            let response = String::from_utf8(errors.response(503, accept)).unwrap();
+            assert!(response.contains("Vary: Accept\r\n"));
+            response.split("\r\n\r\n").nth(1).unwrap().to_string()
+        };
+
+        // browsers
+        assert_eq!(body(Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8")), "<p>down</p>");
+        // api clients, and clients that take anything
+        assert_eq!(body(Some("application/json")), "{}");
+        assert_eq!(body(Some("*/*")), "{}");
+        assert_eq!(body(None), "{}");
+        assert_eq!(body(Some("text/*, application/json;q=0.5")), "<p>down</p>");
+        // neither, or refused
+        assert_eq!(body(Some("image/png")), "down");
+        assert_eq!(body(Some("text/html;q=0, application/*;q=0")), "down");
+    }
+
+    #[test]
+    fn statuses_without_pages_are_plain_text() {
+        let response = String::from_utf8(with_pages().response(504, Some("text/html"))).unwrap();
+
+        assert!(response.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(!response.contains("Vary"));
+    }
+
+    #[test]
+    fn picks_the_most_specific_accept_range() {
+        assert_eq!(quality("text/*;q=0.3, text/html;q=0.7, */*;q=0.5", "text/html"), 0.7);
+        assert_eq!(quality("text/*;q=0.3, text/html;q=0.7, */*;q=0.5", "text/plain"), 0.3);
+        assert_eq!(quality("text/*;q=0.3, text/html;q=0.7, */*;q=0.5", "application/json"), 0.5);
+        assert_eq!(quality("TEXT/HTML ; Q=0.2", "text/html"), 0.2);
+        assert_eq!(quality("image/png", "text/html"), 0.0);
+    }
+}
@@ -0,0 +1,381 @@
+use std::ops::Range;
+use std::sync::{Arc, LazyLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use futures::future::join_all;
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::{now, verbose};
+
+// how fast the latency of a host forgets slow responses
+const LATENCY_DECAY_MILLIS: f64 = 10.0 * 1000.0;
+
+// how long a health probe may take before the host counts as unhealthy
+const HEALTHCHECK_TIMEOUT_MILLIS: u64 = 2 * 1000;
+
+// client for all health probes, so a host that does not answer cannot hold up
+// the others for longer than HEALTHCHECK_TIMEOUT_MILLIS
+static PROBES: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_millis(HEALTHCHECK_TIMEOUT_MILLIS))
+        .build()
+        .expect("the probe client has a valid configuration")
+});
+
+// latency assumed for a host before its first response
+const DEFAULT_LATENCY_MILLIS: f64 = 100.0;
+
+// latency recorded at least for a request the host failed, so failing fast does
+// not make it look fast
+const FAILURE_LATENCY_MILLIS: u64 = 1000;
+
+// share of its weight a host starts with when it comes back healthy
+const SLOW_START_INITIAL_FRACTION: f64 = 0.1;
+
+// the backend pool, read without locking by every request. changes build a new
+// list and swap it in, so requests and health checks never wait on each other
+pub type Hosts = ArcSwap<Vec<Host>>;
+
+#[derive(Clone)]
+pub struct Host {
+    pub url: String,
+    pub healthy: bool,
+    pub weight: u32,
+    // group of the host, lower is preferred
+    pub priority: u32,
+    // when the host last turned healthy
+    pub healthy_since: Option<Instant>,
+    // how long the host takes to ramp up to its full weight once healthy
+    pub slow_start: Duration,
+    // requests currently being proxied to this host
+    pub active: Arc<AtomicUsize>,
+    // response times of this host
+    pub latency: Arc<Latency>,
+    // idle keep-alive connections to this host
+    pub pool: Arc<Pool>,
+}
+
+impl Host {
+    pub fn new(url: &str, weight: u32, priority: u32, slow_start: Duration) -> Host {
+        Host {
+            url: url.to_string(),
+            healthy: false,
+            weight,
+            priority,
+            healthy_since: None,
+            slow_start,
+            active: Arc::new(AtomicUsize::new(0)),
+            latency: Arc::new(Latency::new()),
+            pool: Arc::new(Pool::new(0, Duration::ZERO)),
+        }
+    }
+
+    // number of requests currently being proxied to this host
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&mut self, healthy: bool) {
+        if healthy && !self.healthy {
+            self.healthy_since = Some(Instant::now());
+        }
+        self.healthy = healthy;
+    }
+
+    // weight of the host, ramping up linearly from SLOW_START_INITIAL_FRACTION of
+    // it during the slow start window after the host turned healthy
+    pub fn effective_weight(&self) -> f64 {
+        let weight = self.weight as f64;
+
+        match self.healthy_since {
+            Some(since) if since.elapsed() < self.slow_start => {
+                let progress = since.elapsed().as_secs_f64() / self.slow_start.as_secs_f64();
+                weight * progress.max(SLOW_START_INITIAL_FRACTION)
+            },
+            _ => weight,
+        }
+    }
+}
+
+// peak ewma of the response time of a host: jumps up to a slower response right
+// away, and decays with a time constant of LATENCY_DECAY_MILLIS, towards faster
+// responses as they come in, and towards 0 while the host gets none. a host that
+// was slow once is not shunned forever, it gets picked again once it has cooled
+// down and its next responses tell how fast it is now
+pub struct Latency {
+    // ewma in milliseconds, and when it was last updated
+    state: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl Latency {
+    fn new() -> Latency {
+        Latency { state: std::sync::Mutex::new((DEFAULT_LATENCY_MILLIS, Instant::now())) }
+    }
+
+    fn observe(&self, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let (ewma, updated) = *state;
+        let rtt = rtt.as_secs_f64() * 1000.0;
+
+        let ewma = if rtt > ewma {
+            rtt
+        } else {
+            let w = decay(updated);
+            ewma * w + rtt * (1.0 - w)
+        };
+
+        *state = (ewma, Instant::now());
+    }
+
+    // current ewma in milliseconds, decayed for the time since the last response
+    pub fn millis(&self) -> f64 {
+        let (ewma, updated) = *self.state.lock().unwrap();
+        ewma * decay(updated)
+    }
+}
+
+// weight left to a value last updated at `updated`
+fn decay(updated: Instant) -> f64 {
+    let elapsed = updated.elapsed().as_secs_f64() * 1000.0;
+    (-elapsed / LATENCY_DECAY_MILLIS).exp()
+}
+
+// an in-flight request to a host, counted in the host's active connections
+// until it is dropped
+pub struct Connection {
+    active: Arc<AtomicUsize>,
+    latency: Arc<Latency>,
+}
+
+impl Connection {
+    pub fn open(host: &Host) -> Connection {
+        host.active.fetch_add(1, Ordering::Relaxed);
+        Connection { active: host.active.clone(), latency: host.latency.clone() }
+    }
+
+    // record how long the host took to answer
+    pub fn observe(&self, rtt: Duration) {
+        self.latency.observe(rtt);
+    }
+
+    // record a request the host failed after `elapsed`, as at least a slow answer
+    pub fn fail(&self, elapsed: Duration) {
+        self.latency.observe(elapsed.max(Duration::from_millis(FAILURE_LATENCY_MILLIS)));
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn healthy(host: &Host) -> bool {
+    let response = PROBES.get(format!("http://{}", host.url)).send().await;
+
+
+    match response {
+        Ok(resp) => resp.status().is_success(),
+        Err(_e) => false,
+    }
+}
+
+// probe every host at once, without holding up requests, then swap in their
+// new health
+pub async fn check_health(hosts: &Hosts) {
+    let probed = hosts.load_full();
+    let results: Vec<bool> = join_all(probed.iter().map(healthy)).await;
+
+    for (host, healthy) in probed.iter().zip(&results) {
+        if verbose() > 0 {
+            if *healthy {
+                println!("{} lb [INFO] {} is healthy", now(), host.url);
+            } else {
+                println!("{} lb [WARN] {} is unhealthy", now(), host.url);
+            }
+        }
+    }
+
+    let results: Vec<(&str, bool)> = probed.iter().map(|host| host.url.as_str()).zip(results).collect();
+    update_health(hosts, &results);
+}
+
+// set the health of the hosts by url, ignoring hosts a reload removed meanwhile
+pub fn update_health(hosts: &Hosts, health: &[(&str, bool)]) {
+    hosts.rcu(|current| {
+        let mut hosts = Vec::clone(current);
+        for host in hosts.iter_mut() {
+            if let Some((_, healthy)) = health.iter().find(|(url, _)| *url == host.url) {
+                host.set_healthy(*healthy);
+            }
+        }
+        hosts
+    });
+}
+
+// hosts for the backends, sorted by priority so that every priority group is a
+// contiguous range
+pub async fn initialize_hosts(config: &Config) -> Vec<Host> {
+    let slow_start = Duration::from_millis(config.slow_start_millis);
+    let idle_timeout = Duration::from_millis(config.pool_idle_timeout_millis);
+
+    let mut hosts: Vec<Host> = config.backends.iter()
+        .map(|backend| Host {
+            pool: Arc::new(Pool::new(config.pool_max_idle, idle_timeout)),
+            ..Host::new(&backend.address, backend.weight, backend.priority, slow_start)
+        })
+        .collect();
+    hosts.sort_by_key(|host| host.priority);
+    hosts
+}
+
+// range of the hosts requests should be routed to: the highest priority group
+// with at least `threshold` of its hosts healthy, or else the highest priority
+// group with any healthy host. empty when no host is healthy.
+pub fn active_group(hosts: &[Host], threshold: f64) -> Range<usize> {
+    let mut fallback: Option<Range<usize>> = None;
+    let mut start = 0;
+
+    while start < hosts.len() {
+        let end = start + hosts[start..].iter()
+            .take_while(|host| host.priority == hosts[start].priority)
+            .count();
+
+        let healthy = hosts[start..end].iter().filter(|host| host.healthy).count();
+        if healthy > 0 {
+            if healthy as f64 >= threshold * (end - start) as f64 {
+                return start..end;
+            }
+            fallback.get_or_insert(start..end);
+        }
+
+        start = end;
+    }
+
+    fallback.unwrap_or(0..0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tests::hosts;
+
+    fn latency(ewma: f64, age: Duration) -> Latency {
+        Latency { state: std::sync::Mutex::new((ewma, Instant::now() - age)) }
+    }
+
+    #[test]
+    fn latency_starts_at_the_default() {
+        assert!((Latency::new().millis() - DEFAULT_LATENCY_MILLIS).abs() < 1.0);
+    }
+
+    #[test]
+    fn latency_decays_while_the_host_gets_no_responses() {
+        // a single spike is mostly forgotten after a few time constants
+        let spike = latency(5000.0, Duration::from_millis(3 * LATENCY_DECAY_MILLIS as u64));
+        assert!(spike.millis() < 5000.0 * 0.06);
+
+        // and a slower response still counts in full right away
+        spike.observe(Duration::from_millis(800));
+        assert!(spike.millis() > 799.0);
+    }
+
+    #[test]
+    fn failures_count_as_slow_responses() {
+        let host = Host::new("a", 1, 0, Duration::ZERO);
+        let connection = Connection::open(&host);
+
+        connection.fail(Duration::from_millis(1));
+        assert!(host.latency.millis() >= FAILURE_LATENCY_MILLIS as f64 - 1.0);
+    }
+
+    // hosts sorted by priority, healthy or not
+    fn pool(groups: &[(u32, bool)]) -> Vec<Host> {
+        let mut pool = hosts(&vec![1; groups.len()]);
+        for (host, (priority, healthy)) in pool.iter_mut().zip(groups) {
+            host.priority = *priority;
+            host.healthy = *healthy;
+        }
+        pool
+    }
+
+    #[test]
+    fn routes_to_the_first_group_healthy_enough() {
+        let hosts = pool(&[(0, true), (0, false), (1, true), (1, true)]);
+
+        assert_eq!(active_group(&hosts, 0.5), 0..2);
+        assert_eq!(active_group(&hosts, 0.6), 2..4);
+        // any healthy host is enough at 0, every host is needed at 1
+        assert_eq!(active_group(&hosts, 0.0), 0..2);
+        assert_eq!(active_group(&hosts, 1.0), 2..4);
+    }
+
+    #[test]
+    fn skips_groups_without_healthy_hosts() {
+        let hosts = pool(&[(0, false), (0, false), (1, false), (2, true)]);
+        assert_eq!(active_group(&hosts, 0.0), 3..4);
+        assert_eq!(active_group(&hosts, 1.0), 3..4);
+    }
+
+    #[test]
+    fn falls_back_to_the_first_group_with_a_healthy_host() {
+        // no group is healthy enough, the preferred one still has a host
+        let hosts = pool(&[(0, true), (0, false), (1, true), (1, false), (1, false)]);
+        assert_eq!(active_group(&hosts, 1.0), 0..2);
+
+        assert_eq!(active_group(&pool(&[(0, false), (1, false)]), 0.5), 0..0);
+        assert_eq!(active_group(&[], 0.5), 0..0);
+    }
+
+    fn ramping(weight: u32, slow_start: Duration, healthy_for: Duration) -> Host {
+        Host {
+            healthy: true,
+            healthy_since: Some(Instant::now() - healthy_for),
+            ..Host::new("a", weight, 0, slow_start)
+        }
+    }
+
+    #[test]
+    fn ramps_up_linearly_during_slow_start() {
+        let window = Duration::from_secs(10);
+
+        let halfway = ramping(10, window, window / 2).effective_weight();
+        assert!((halfway - 5.0).abs() < 0.1, "weight {}", halfway);
+
+        // full weight after the window, or without slow start
+        assert_eq!(ramping(10, window, window * 2).effective_weight(), 10.0);
+        assert_eq!(ramping(10, Duration::ZERO, Duration::ZERO).effective_weight(), 10.0);
+        assert_eq!(Host::new("a", 10, 0, window).effective_weight(), 10.0);
+    }
+
+    #[test]
+    fn starts_slow_start_at_a_tenth_of_the_weight() {
+        let window = Duration::from_secs(10);
+
+        let start = ramping(10, window, Duration::ZERO).effective_weight();
+        assert!((start - 10.0 * SLOW_START_INITIAL_FRACTION).abs() < 1e-6, "weight {}", start);
+        let early = ramping(10, window, window / 20).effective_weight();
+        assert!((early - 10.0 * SLOW_START_INITIAL_FRACTION).abs() < 1e-6, "weight {}", early);
+    }
+
+    #[test]
+    fn slow_start_begins_when_a_host_turns_healthy() {
+        let mut host = Host::new("a", 10, 0, Duration::from_secs(10));
+
+        host.set_healthy(true);
+        let since = host.healthy_since;
+        assert!(since.is_some());
+
+        // staying healthy does not restart the ramp
+        host.set_healthy(true);
+        assert_eq!(host.healthy_since, since);
+
+        host.set_healthy(false);
+        std::thread::sleep(Duration::from_millis(1));
+        host.set_healthy(true);
+        assert_ne!(host.healthy_since, since);
+    }
+}
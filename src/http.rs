@@ -0,0 +1,956 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+use crate::verbose;
+
+// most bytes in the head of a message, or in the trailers of a chunked body
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// most header fields in the head of a message
+const MAX_HEADERS: usize = 100;
+
+// longest chunk-size line, extensions included
+const MAX_CHUNK_LINE: usize = 4 * 1024;
+
+// the head of an http request or response as read off the wire, its body is
+// left in the reader to be streamed with `copy_body`
+pub struct Message {
+    // the bytes to forward, start line and headers up to the empty line
+    pub head: Vec<u8>,
+    // request or status line, without the line ending
+    pub start_line: String,
+    pub headers: Vec<(String, String)>,
+    // how the body following the head is framed
+    pub body: Body,
+}
+
+pub enum Body {
+    // exactly this many bytes
+    Length(u64),
+    // chunked transfer coding, up to the last chunk and the trailers
+    Chunked,
+    // everything up to the end of the connection, responses only
+    Close,
+}
+
+impl Message {
+    // value of the first header with this name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // value of the cookie with this name, from the Cookie headers
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    // remove every header with this name
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.head = self.head.split_inclusive(|b| *b == b'\n')
+            .filter(|line| !line.split(|b| *b == b':').next().unwrap_or_default().eq_ignore_ascii_case(name.as_bytes()))
+            .flatten()
+            .copied()
+            .collect();
+    }
+
+    // whether the client waits for a 100 Continue before sending the body, which
+    // only HTTP/1.1 clients may ask for
+    pub fn expects_continue(&self) -> bool {
+        self.version() == "HTTP/1.1" && self.header("Expect").is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    // add a header at the end of the head
+    pub fn insert_header(&mut self, name: &str, value: &str) {
+        let line = format!("{}: {}\r\n", name, value);
+
+        // before the empty line closing the head
+        let at = self.head.len().saturating_sub(2);
+        self.head.splice(at..at, line.bytes());
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    // request method, for requests
+    pub fn method(&self) -> &str {
+        self.start_line.split(' ').next().unwrap_or("")
+    }
+
+    // whether repeating the request has the same effect as sending it once,
+    // for requests
+    pub fn idempotent(&self) -> bool {
+        matches!(self.method(), "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
+    }
+
+    // request target, for requests
+    pub fn path(&self) -> &str {
+        self.start_line.split(' ').nth(1).unwrap_or("")
+    }
+
+    // status code, for responses
+    pub fn status(&self) -> Option<u16> {
+        self.start_line.split(' ').nth(1)?.parse().ok()
+    }
+
+    // protocol version, first on a status line and last on a request line
+    pub fn version(&self) -> &str {
+        if self.start_line.starts_with("HTTP/") {
+            self.start_line.split(' ').next().unwrap_or("")
+        } else {
+            self.start_line.split(' ').nth(2).unwrap_or("")
+        }
+    }
+
+    // whether the connection stays open after this message: by default from
+    // HTTP/1.1 on, and only when asked for with HTTP/1.0
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| self.headers.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case(option));
+
+        if has_option("close") {
+            return false;
+        }
+
+        self.version() == "HTTP/1.1" || has_option("keep-alive")
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    // the connection failed or closed before the end of the head
+    Io(io::Error),
+    // the request or status line is not ascii, or is empty
+    StartLine,
+    // a header line is not a `name: value` field
+    Header(String),
+    // a header line continues the previous one, obsolete line folding
+    ObsFold,
+    // a line ends in a bare LF, or has a bare CR in it
+    LineEnding,
+    // the Content-Length values are not a single number
+    ContentLength(String),
+    // both Content-Length and Transfer-Encoding are set
+    ConflictingFraming,
+    // a request with a transfer coding other than chunked last
+    TransferEncoding(String),
+    // the head is longer than MAX_HEAD_SIZE
+    HeadTooLarge,
+    // the head has more than MAX_HEADERS fields
+    TooManyHeaders,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "could not read message: {}", e),
+            ParseError::StartLine => write!(f, "invalid start line"),
+            ParseError::Header(line) => write!(f, "invalid header line {:?}", line),
+            ParseError::ObsFold => write!(f, "obsolete line folding in headers"),
+            ParseError::LineEnding => write!(f, "line not ending in CRLF"),
+            ParseError::ContentLength(value) => write!(f, "invalid Content-Length {:?}", value),
+            ParseError::ConflictingFraming => write!(f, "both Content-Length and Transfer-Encoding are set"),
+            ParseError::TransferEncoding(value) => write!(f, "unsupported Transfer-Encoding {:?}", value),
+            ParseError::HeadTooLarge => write!(f, "head larger than {} bytes", MAX_HEAD_SIZE),
+            ParseError::TooManyHeaders => write!(f, "more than {} header fields", MAX_HEADERS),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// read the start line and headers of a message, leaving the body in the reader
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Message, ParseError> {
+
+    let mut buf: Vec<u8> = vec![];
+    let mut line: Vec<u8> = vec![];
+    let mut content_length: Option<u64> = None;
+
+    // read first header line, either request or response line
+    read_head_line(reader, &mut line, &mut buf).await?;
+    if verbose() >= 1 {
+        print!("{}", String::from_utf8_lossy(&line));
+    }
+    let start_line = match std::str::from_utf8(trim_line_ending(&line)) {
+        Ok(start_line) if start_line.is_ascii() && !start_line.trim().is_empty() => start_line.to_string(),
+        _ => return Err(ParseError::StartLine),
+    };
+
+    // read the header
+    let mut headers: Vec<(String, String)> = vec![];
+    loop {
+        read_head_line(reader, &mut line, &mut buf).await?;
+        if verbose() >= 2 {
+            print!("{}", String::from_utf8_lossy(&line));
+        }
+
+        let field = trim_line_ending(&line);
+        if field.is_empty() { break };
+
+        // a continuation line would be read as its own header by some parsers
+        // and folded into the previous one by others
+        if field[0] == b' ' || field[0] == b'\t' {
+            return Err(ParseError::ObsFold);
+        }
+
+        if headers.len() == MAX_HEADERS {
+            return Err(ParseError::TooManyHeaders);
+        }
+
+        let (name, value) = parse_field(field)
+            .ok_or_else(|| ParseError::Header(String::from_utf8_lossy(field).into_owned()))?;
+
+        // repeated Content-Length values, in one header or several, are only
+        // accepted when they all agree
+        if name.eq_ignore_ascii_case("Content-Length") {
+            for length in value.split(',') {
+                let length = parse_content_length(length.trim())
+                    .ok_or_else(|| ParseError::ContentLength(value.clone()))?;
+                if content_length.is_some_and(|other| other != length) {
+                    return Err(ParseError::ContentLength(value.clone()));
+                }
+                content_length = Some(length);
+            }
+        }
+        headers.push((name, value));
+    }
+
+    // a message framed both ways is read differently by different parsers,
+    // which is what request smuggling relies on
+    let codings: Vec<&str> = headers.iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|coding| coding.trim())
+        .collect();
+
+    if !codings.is_empty() && content_length.is_some() {
+        return Err(ParseError::ConflictingFraming);
+    }
+
+    // the body is chunked when that is the last transfer coding
+    let chunked = codings.last().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+
+    // the length of a request body is unknown otherwise
+    let request = !start_line.starts_with("HTTP/");
+    if request && !codings.is_empty() && !chunked {
+        return Err(ParseError::TransferEncoding(codings.join(", ")));
+    }
+
+    // a request without framing has no body, a response runs until the
+    // connection closes, RFC 9112 section 6.3
+    let body = match content_length {
+        _ if chunked => Body::Chunked,
+        Some(length) => Body::Length(length),
+        None if request => Body::Length(0),
+        None => Body::Close,
+    };
+
+    Ok(Message { head: buf, start_line, headers, body })
+}
+
+// read the head of a request
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Message, ParseError> {
+    read_head(reader).await
+}
+
+// read the head of the response to a request with `method`. responses to HEAD,
+// informational ones, 204 and 304 end with the head whatever their headers say,
+// after a 101 the connection no longer carries http
+pub async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R, method: &str) -> Result<Message, ParseError> {
+    let mut response = read_head(reader).await?;
+
+    let status = response.status().ok_or(ParseError::StartLine)?;
+    if status == 101 {
+        response.body = Body::Close;
+    } else if method == "HEAD" || (100..200).contains(&status) || status == 204 || status == 304 {
+        response.body = Body::Length(0);
+    }
+
+    Ok(response)
+}
+
+// split a `name: value` header field, the name is a token directly followed by
+// the colon and the value is stripped of the optional whitespace around it.
+// values are decoded lossily, the head is forwarded byte for byte anyway
+fn parse_field(field: &[u8]) -> Option<(String, String)> {
+    let colon = field.iter().position(|b| *b == b':')?;
+    let (name, value) = (&field[..colon], &field[colon + 1..]);
+
+    if name.is_empty() || !name.iter().all(|b| is_token(*b)) {
+        return None;
+    }
+
+    let value = value.trim_ascii();
+    if value.iter().any(|b| b.is_ascii_control() && *b != b'\t') {
+        return None;
+    }
+
+    Some((String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned()))
+}
+
+// 1*DIGIT, without the sign or whitespace `parse` would let through
+fn parse_content_length(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// tchar from RFC 9110
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r\n").unwrap_or(line)
+}
+
+// stream the body framed as `body` from `reader` to `writer` as is, a buffer of
+// the reader at a time, so memory use does not grow with the size of the body
+pub async fn copy_body<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, body: &Body) -> io::Result<()> {
+    relay_body(reader, writer, body, |data| {
+        if verbose() >= 3 {
+            print!("{}", String::from_utf8_lossy(data));
+        }
+    }).await?;
+
+    if verbose() >= 3 {
+        println!();
+    }
+
+    writer.flush().await
+}
+
+// relay the body and hand the decoded data to `on_data` as it passes by
+async fn relay_body<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, body: &Body, mut on_data: impl FnMut(&[u8])) -> io::Result<()> {
+    match body {
+        Body::Length(length) => copy_exact(reader, writer, *length, &mut on_data).await,
+        Body::Chunked => copy_chunked(reader, writer, &mut on_data).await,
+        Body::Close => copy_to_end(reader, writer, &mut on_data).await,
+    }
+}
+
+// relay a chunked body with its chunk extensions and trailers
+async fn copy_chunked<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, on_data: &mut impl FnMut(&[u8])) -> io::Result<()> {
+    let mut line: Vec<u8> = vec![];
+    let mut raw: Vec<u8> = vec![];
+
+    loop {
+        // chunk-size [ ;chunk-ext ] CRLF
+        raw.clear();
+        read_line(reader, &mut line, &mut raw, MAX_CHUNK_LINE).await?;
+        let size = parse_chunk_size(trim_line_ending(&line)).ok_or_else(|| invalid_data("invalid chunk size"))?;
+        writer.write_all(&raw).await?;
+
+        // the last chunk is followed by trailer fields up to an empty line,
+        // within the same limit as a head
+        if size == 0 {
+            let mut trailers = 0;
+            loop {
+                raw.clear();
+                read_line(reader, &mut line, &mut raw, MAX_HEAD_SIZE - trailers).await?;
+                trailers += raw.len();
+                writer.write_all(&raw).await?;
+                if line == b"\r\n" {
+                    return Ok(());
+                }
+            }
+        }
+
+        // chunk-data CRLF
+        copy_exact(reader, writer, size, on_data).await?;
+
+        raw.clear();
+        read_line(reader, &mut line, &mut raw, 2).await?;
+        if line != b"\r\n" {
+            return Err(invalid_data("chunk data is not followed by CRLF"));
+        }
+        writer.write_all(&raw).await?;
+    }
+}
+
+// chunk-size [ BWS ;chunk-ext ], the size in at most 16 hex digits so it fits
+fn parse_chunk_size(line: &[u8]) -> Option<u64> {
+    let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    if digits == 0 || digits > 16 {
+        return None;
+    }
+
+    let extensions = &line[digits..];
+    let extensions = &extensions[extensions.iter().take_while(|b| **b == b' ' || **b == b'\t').count()..];
+    if !extensions.is_empty() && extensions[0] != b';' {
+        return None;
+    }
+
+    u64::from_str_radix(std::str::from_utf8(&line[..digits]).ok()?, 16).ok()
+}
+
+// relay exactly `length` bytes
+async fn copy_exact<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, mut length: u64, on_data: &mut impl FnMut(&[u8])) -> io::Result<()> {
+    while length > 0 {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let n = buf.len().min(usize::try_from(length).unwrap_or(usize::MAX));
+        writer.write_all(&buf[..n]).await?;
+        on_data(&buf[..n]);
+
+        reader.consume(n);
+        length -= n as u64;
+    }
+
+    Ok(())
+}
+
+// relay everything until the other side closes the connection
+async fn copy_to_end<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, on_data: &mut impl FnMut(&[u8])) -> io::Result<()> {
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let n = buf.len();
+        writer.write_all(buf).await?;
+        on_data(buf);
+
+        reader.consume(n);
+    }
+}
+
+// read the next line into `line`, and append it to `raw`. lines have to end in
+// CRLF with no CR before that, a lenient parser further down the line could
+// split them differently. at most `limit` bytes are read, so a peer cannot make
+// the line grow without bounds
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>, raw: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    line.clear();
+    let read = reader.take(limit as u64).read_until(b'\n', line).await?;
+    if !line.ends_with(b"\n") {
+        if read == limit {
+            return Err(invalid_data("line too long"));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with(b"\r\n") || line[..line.len() - 2].contains(&b'\r') {
+        return Err(invalid_data("line not ending in CRLF"));
+    }
+    raw.extend_from_slice(line);
+    Ok(())
+}
+
+// read_line for the head, within what is left of MAX_HEAD_SIZE, telling heads
+// that are too large and bad line endings from failed reads
+async fn read_head_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>, raw: &mut Vec<u8>) -> Result<(), ParseError> {
+    let limit = MAX_HEAD_SIZE - raw.len();
+    read_line(reader, line, raw, limit).await.map_err(|e| match e.kind() {
+        _ if line.len() == limit && !line.ends_with(b"\n") => ParseError::HeadTooLarge,
+        io::ErrorKind::InvalidData => ParseError::LineEnding,
+        _ => ParseError::Io(e),
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// a stream whose reads and writes fail with TimedOut when they make no progress
+// for `timeout`. the clock only runs while an operation is waiting on the
+// stream, so time spent on the other side of a copy does not count
+pub struct Timed<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    // deadline of the operation waiting on the stream, if any
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Timed<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Timed<S> {
+        Timed { inner, timeout, deadline: None }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.deadline = None;
+    }
+
+    // restart the clock once an operation went through, or fail it once it has
+    // been waiting for too long
+    fn poll_progress<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.deadline = None;
+            return poll;
+        }
+
+        if let Some(timeout) = self.timeout {
+            let deadline = self.deadline.get_or_insert_with(|| Box::pin(sleep(timeout)));
+            if deadline.as_mut().poll(cx).is_ready() {
+                self.deadline = None;
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Timed<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.poll_progress(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Timed<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.poll_progress(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.poll_progress(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// a reader that remembers whether it failed or ran out, to tell the errors of
+// the side it reads from apart from those of the side a copy writes to
+pub struct Watched<R> {
+    inner: R,
+    ended: bool,
+}
+
+impl<R> Watched<R> {
+    pub fn new(inner: R) -> Watched<R> {
+        Watched { inner, ended: false }
+    }
+
+    // whether a read failed or found the end of the stream
+    pub fn ended(&self) -> bool {
+        self.ended
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Watched<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Err(_))) || (poll.is_ready() && buf.filled().len() == filled && buf.remaining() > 0) {
+            this.ended = true;
+        }
+        poll
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for Watched<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_fill_buf(cx);
+        if matches!(poll, Poll::Ready(Err(_)) | Poll::Ready(Ok([]))) {
+            this.ended = true;
+        }
+        poll
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // read a whole message, returning the bytes relayed, the decoded body and
+    // what is left in the reader
+    async fn read(raw: &[u8]) -> (Message, Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut reader = raw;
+        let message = read_head(&mut reader).await.unwrap();
+
+        let mut relayed = message.head.clone();
+        let mut body = vec![];
+        relay_body(&mut reader, &mut relayed, &message.body, |data| body.extend_from_slice(data)).await.unwrap();
+
+        (message, relayed, body, reader.to_vec())
+    }
+
+    async fn parse(raw: &[u8]) -> Result<Message, ParseError> {
+        read_head(&mut &raw[..]).await
+    }
+
+    async fn decode(chunked: &[u8]) -> io::Result<Vec<u8>> {
+        let mut body = vec![];
+        relay_body(&mut &chunked[..], &mut vec![], &Body::Chunked, |data| body.extend_from_slice(data)).await?;
+        Ok(body)
+    }
+
+    #[tokio::test]
+    async fn reads_content_length_body() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let (message, relayed, body, rest) = read(raw).await;
+
+        assert_eq!(relayed, raw);
+        assert_eq!(body, b"hello");
+        assert_eq!(message.start_line, "HTTP/1.1 200 OK");
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relays_chunked_body_as_is() {
+        // the example from the wikipedia article on chunked transfer encoding
+        let raw = b"HTTP/1.1 200 OK\r\n\
+            Content-Type: text/plain\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            4\r\nWiki\r\n\
+            6\r\npedia \r\n\
+            E\r\nin \r\n\r\nchunks.\r\n\
+            0\r\n\
+            \r\n";
+        let (_, relayed, _, rest) = read(raw).await;
+
+        assert_eq!(relayed, raw);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn decodes_chunks() {
+        let body = decode(b"4\r\nWiki\r\n6\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n").await.unwrap();
+        assert_eq!(body, b"Wikipedia in \r\n\r\nchunks.");
+    }
+
+    #[tokio::test]
+    async fn reads_chunk_extensions_and_trailers() {
+        let raw = b"HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: Expires\r\n\
+            \r\n\
+            1a; ieof\r\nabcdefghijklmnopqrstuvwxyz\r\n\
+            10;name=\"quoted;value\"\r\n1234567890abcdef\r\n\
+            0\r\n\
+            Expires: Sat, 27 Mar 2004 21:12:00 GMT\r\n\
+            X-Checksum: 42\r\n\
+            \r\n";
+        let (_, relayed, body, rest) = read(raw).await;
+
+        assert_eq!(relayed, raw);
+        assert!(rest.is_empty());
+        assert_eq!(body, b"abcdefghijklmnopqrstuvwxyz1234567890abcdef");
+    }
+
+    #[tokio::test]
+    async fn chunked_wins_over_other_codings() {
+        let raw = b"HTTP/1.1 200 OK\r\n\
+            Content-Encoding: gzip\r\n\
+            Transfer-Encoding: gzip, chunked\r\n\
+            \r\n\
+            3\r\n\x1f\x8b\x08\r\n\
+            0\r\n\r\n";
+        let (_, relayed, _, _) = read(raw).await;
+
+        assert_eq!(relayed, raw);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_end_of_the_chunked_body() {
+        let raw = b"HTTP/1.1 200 OK\r\n\
+            transfer-encoding: chunked\r\n\
+            \r\n\
+            5\r\nhello\r\n\
+            0\r\n\r\n\
+            HTTP/1.1 204 No Content\r\n\r\n";
+        let (_, relayed, _, rest) = read(raw).await;
+
+        assert!(relayed.ends_with(b"0\r\n\r\n"));
+        assert_eq!(rest, b"HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_chunks() {
+        assert!(decode(b"zz\r\nhello\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b"5\r\nhello world\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b"5\r\nhel").await.is_err());
+        assert!(decode(b"5\r\nhello\r\n0\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_large_bodies_through_a_small_buffer() {
+        let body = vec![b'x'; 1 << 20];
+        let mut raw = format!("POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        raw.extend_from_slice(&body);
+
+        let mut reader = tokio::io::BufReader::with_capacity(64, &raw[..]);
+        let message = read_head(&mut reader).await.unwrap();
+
+        let mut relayed = vec![];
+        let mut largest = 0;
+        relay_body(&mut reader, &mut relayed, &message.body, |data| largest = largest.max(data.len())).await.unwrap();
+
+        assert_eq!(relayed, body);
+        assert!(largest <= 64);
+    }
+
+    #[tokio::test]
+    async fn inserts_headers_before_the_body() {
+        let mut message = read_head(&mut &b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]).await.unwrap();
+        message.insert_header("Set-Cookie", "lb=1");
+
+        assert_eq!(message.head, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nSet-Cookie: lb=1\r\n\r\n");
+        assert_eq!(message.header("set-cookie"), Some("lb=1"));
+    }
+
+    #[tokio::test]
+    async fn parses_headers_case_insensitively_with_optional_whitespace() {
+        let raw = b"POST / HTTP/1.1\r\ncontent-length:5\r\nX-Name: caf\xc3\xa9 \t\r\nX-Raw:\xff\r\nX-Empty:\r\n\r\nhello";
+        let (message, _, body, _) = read(raw).await;
+
+        assert_eq!(body, b"hello");
+        assert_eq!(message.header("Content-Length"), Some("5"));
+        assert_eq!(message.header("x-name"), Some("café"));
+        assert_eq!(message.header("X-Raw"), Some("\u{fffd}"));
+        assert_eq!(message.header("X-Empty"), Some(""));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_heads() {
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nHost\r\n\r\n").await, Err(ParseError::Header(_))));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n").await, Err(ParseError::Header(_))));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\n: a\r\n\r\n").await, Err(ParseError::Header(_))));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nX-A: a\r\n b\r\n\r\n").await, Err(ParseError::ObsFold)));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nContent-Length: five\r\n\r\n").await, Err(ParseError::ContentLength(_))));
+        assert!(matches!(parse(b"GET /\xff HTTP/1.1\r\n\r\n").await, Err(ParseError::StartLine)));
+        assert!(matches!(parse(b"\r\n").await, Err(ParseError::StartLine)));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nHost: a\r\n").await, Err(ParseError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_ambiguous_framing() {
+        // CL.TE and TE.CL
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n").await,
+            Err(ParseError::ConflictingFraming)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n").await,
+            Err(ParseError::ConflictingFraming)
+        ));
+
+        // conflicting or malformed lengths
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n").await,
+            Err(ParseError::ContentLength(_))
+        ));
+        assert!(matches!(parse(b"POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n").await, Err(ParseError::ContentLength(_))));
+        assert!(matches!(parse(b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n").await, Err(ParseError::ContentLength(_))));
+        assert!(parse(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\n").await.is_ok());
+
+        // request bodies of unknown length
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n").await,
+            Err(ParseError::TransferEncoding(_))
+        ));
+        assert!(parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n").await.is_ok());
+
+        // bare LF and CR
+        assert!(matches!(parse(b"GET / HTTP/1.1\nHost: a\r\n\r\n").await, Err(ParseError::LineEnding)));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nHost: a\n\r\n").await, Err(ParseError::LineEnding)));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nX-A: a\rb\r\n\r\n").await, Err(ParseError::LineEnding)));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_chunk_sizes() {
+        assert!(decode(b"+5\r\nhello\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b" 5\r\nhello\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b"5 x\r\nhello\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b"10000000000000005\r\nhello\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b"5\nhello\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b"5\r\nhello\n0\r\n\r\n").await.is_err());
+        assert_eq!(decode(b"5 ;ext\r\nhello\r\n0\r\n\r\n").await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn reads_unframed_responses_until_the_connection_closes() {
+        let raw = b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nno length\r\n\r\nat all";
+        let (message, relayed, body, rest) = read(raw).await;
+
+        assert!(matches!(message.body, Body::Close));
+        assert_eq!(relayed, raw);
+        assert_eq!(body, b"no length\r\n\r\nat all");
+        assert!(rest.is_empty());
+
+        let response = read_response(&mut &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n"[..], "GET").await.unwrap();
+        assert!(matches!(response.body, Body::Close));
+    }
+
+    #[tokio::test]
+    async fn requests_without_framing_have_no_body() {
+        let request = read_request(&mut &b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET /next HTTP/1.1\r\n"[..]).await.unwrap();
+        assert!(matches!(request.body, Body::Length(0)));
+    }
+
+    #[tokio::test]
+    async fn some_responses_never_have_a_body() {
+        async fn length(raw: &[u8], method: &str) -> Option<u64> {
+            match read_response(&mut &raw[..], method).await.unwrap().body {
+                Body::Length(length) => Some(length),
+                _ => None,
+            }
+        }
+
+        assert_eq!(length(b"HTTP/1.1 200 OK\r\nContent-Length: 42\r\n\r\n", "HEAD").await, Some(0));
+        assert_eq!(length(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n", "HEAD").await, Some(0));
+        assert_eq!(length(b"HTTP/1.1 204 No Content\r\n\r\n", "DELETE").await, Some(0));
+        assert_eq!(length(b"HTTP/1.1 304 Not Modified\r\nContent-Length: 42\r\n\r\n", "GET").await, Some(0));
+        assert_eq!(length(b"HTTP/1.1 100 Continue\r\n\r\n", "POST").await, Some(0));
+        assert_eq!(length(b"HTTP/1.1 200 OK\r\nContent-Length: 42\r\n\r\n", "GET").await, Some(42));
+        assert_eq!(length(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n", "GET").await, None);
+
+        assert!(matches!(read_response(&mut &b"HTTP/1.1 OK\r\n\r\n"[..], "GET").await, Err(ParseError::StartLine)));
+    }
+
+    #[tokio::test]
+    async fn keeps_alive_by_version_and_connection_header() {
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap().keep_alive());
+        assert!(!parse(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap().keep_alive());
+        assert!(!parse(b"GET / HTTP/1.0\r\n\r\n").await.unwrap().keep_alive());
+        assert!(parse(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").await.unwrap().keep_alive());
+        assert!(parse(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap().keep_alive());
+        assert!(!parse(b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap().keep_alive());
+        assert!(!parse(b"HTTP/1.1 200 OK\r\nConnection: keep-alive, close\r\n\r\n").await.unwrap().keep_alive());
+    }
+
+    #[tokio::test]
+    async fn times_out_only_waiting_on_the_stream() {
+        let timeout = Some(Duration::from_millis(50));
+
+        // a slow client: the body takes longer than the timeout, but the
+        // backend side never waits
+        let (mut client, source) = tokio::io::duplex(64);
+        let (sink, mut backend) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for byte in b"hello" {
+                sleep(Duration::from_millis(30)).await;
+                client.write_all(&[*byte]).await.unwrap();
+            }
+        });
+        let mut sink = Timed::new(sink, timeout);
+        copy_body(&mut tokio::io::BufReader::new(source), &mut sink, &Body::Length(5)).await.unwrap();
+
+        let mut body = [0; 5];
+        tokio::io::AsyncReadExt::read_exact(&mut backend, &mut body).await.unwrap();
+        assert_eq!(&body, b"hello");
+
+        // a backend that stops answering
+        let (_silent, stalled) = tokio::io::duplex(64);
+        let mut stalled = tokio::io::BufReader::new(Timed::new(stalled, timeout));
+        let e = stalled.fill_buf().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_heads() {
+        let mut raw = b"GET / HTTP/1.1\r\nX-Big: ".to_vec();
+        raw.extend(vec![b'a'; MAX_HEAD_SIZE]);
+        raw.extend_from_slice(b"\r\n\r\n");
+        assert!(matches!(parse(&raw).await, Err(ParseError::HeadTooLarge)));
+
+        // many lines that are each short, but add up
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..MAX_HEADERS {
+            raw.extend_from_slice(format!("X-{}: {}\r\n", i, "a".repeat(1000)).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        assert!(matches!(parse(&raw).await, Err(ParseError::HeadTooLarge)));
+
+        // a head just under the limit is fine
+        let mut raw = b"GET / HTTP/1.1\r\nX-Big: ".to_vec();
+        raw.extend(vec![b'a'; MAX_HEAD_SIZE - raw.len() - 4]);
+        raw.extend_from_slice(b"\r\n\r\n");
+        assert!(parse(&raw).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_headers() {
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..=MAX_HEADERS {
+            raw.extend_from_slice(format!("X-{}: a\r\n", i).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        assert!(matches!(parse(&raw).await, Err(ParseError::TooManyHeaders)));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_chunk_lines() {
+        let mut chunked = b"5;ext=".to_vec();
+        chunked.extend(vec![b'a'; MAX_CHUNK_LINE]);
+        chunked.extend_from_slice(b"\r\nhello\r\n0\r\n\r\n");
+        assert_eq!(decode(&chunked).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut chunked = b"0\r\n".to_vec();
+        for _ in 0..MAX_HEAD_SIZE / 1000 + 1 {
+            chunked.extend_from_slice(format!("X-T: {}\r\n", "a".repeat(1000)).as_bytes());
+        }
+        chunked.extend_from_slice(b"\r\n");
+        assert_eq!(decode(&chunked).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn tells_which_side_of_a_copy_failed() {
+        // the reader runs out halfway through the body
+        let mut short = Watched::new(&b"hello"[..]);
+        assert!(copy_body(&mut short, &mut vec![], &Body::Length(10)).await.is_err());
+        assert!(short.ended());
+
+        // the writer goes away
+        let (writer, reader) = tokio::io::duplex(64);
+        drop(reader);
+        let mut full = Watched::new(&b"hello"[..]);
+        assert!(copy_body(&mut full, &mut { writer }, &Body::Length(5)).await.is_err());
+        assert!(!full.ended());
+    }
+
+    #[tokio::test]
+    async fn removes_headers() {
+        let mut request = parse(b"POST / HTTP/1.1\r\nHost: a\r\nexpect: 100-continue\r\nContent-Length: 5\r\n\r\n").await.unwrap();
+        assert!(request.expects_continue());
+
+        request.remove_header("Expect");
+        assert!(!request.expects_continue());
+        assert_eq!(request.head, b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\n");
+
+        let request = parse(b"POST / HTTP/1.0\r\nExpect: 100-continue\r\n\r\n").await.unwrap();
+        assert!(!request.expects_continue());
+    }
+
+    #[tokio::test]
+    async fn finds_cookies() {
+        let request = parse(b"GET / HTTP/1.1\r\nCookie: a=1;  lb_backend=abc ;b=2\r\nCookie: c=3\r\n\r\n").await.unwrap();
+
+        assert_eq!(request.cookie("a"), Some("1"));
+        assert_eq!(request.cookie("lb_backend"), Some("abc"));
+        assert_eq!(request.cookie("b"), Some("2"));
+        assert_eq!(request.cookie("c"), Some("3"));
+        assert_eq!(request.cookie("lb"), None);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n").await.unwrap().cookie("a"), None);
+    }
+}
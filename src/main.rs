@@ -1,24 +1,48 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{Read, Write, BufRead, BufReader};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::io::{self, ErrorKind};
 use std::sync::{Arc};
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::error::{Error};
+use std::time::Instant;
 
-use tokio::sync::{Mutex};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{ReadHalf, WriteHalf};
 use tokio::time::{sleep, Duration};
 
 use chrono::prelude::*;
 
-const HEALTHCHECK_PERIOD_MILLIS: u64 = 1 * 60 * 1000;
-
-// verbosity level
+mod cli;
+mod config;
+mod errors;
+mod host;
+mod http;
+mod pool;
+mod reload;
+mod sticky;
+mod strategy;
+
+use clap::Parser;
+
+use cli::{Cli, Command, ConfigArgs};
+use config::Config;
+use host::{active_group, check_health, healthy, initialize_hosts, update_health, Connection, Hosts};
+use errors::ErrorResponses;
+use http::{copy_body, read_request, read_response, Body, Message, ParseError, Timed, Watched};
+use pool::Pool;
+use sticky::Sticky;
+use strategy::{Request, Strategy};
+
+// verbosity level, set from the config at startup
 // 0: none
 // 1: request line only
 // 2: request line and headers
 // 3: request line, headers, and body
-const VERBOSE: u8 = 1;
+static VERBOSE: AtomicU8 = AtomicU8::new(1);
 
-fn strip(s: String) -> String {
-    s.chars().filter(|c| !c.is_whitespace()).collect()
+fn verbose() -> u8 {
+    VERBOSE.load(Ordering::Relaxed)
 }
 
 fn now() -> String {
@@ -26,202 +50,367 @@ fn now() -> String {
 }
 
 
-fn read_http(stream: &mut TcpStream) -> Vec<u8> {
-    
-    let mut reader = BufReader::new(stream);
-    let mut buf: Vec<u8> = vec![];
-    let mut line: Vec<u8> = vec![];
-    let mut content_length: usize = 0;
-
-    // read first header line, either request or response line
-    match reader.read_until(b'\n', &mut line) {
-        Ok(_n) => {
-            if VERBOSE >= 1 {
-                print!("{}", String::from_utf8(line.clone()).unwrap());
-            }
-
-            buf.append(&mut line);
-        },
-        Err(_e) => println!("could not read")
-    }
-
-    // read the header
-    loop {
-        match reader.read_until(b'\n', &mut line) {
-            Ok(_n) => {
-                let header_line = String::from_utf8(line.clone()).unwrap();
-                if VERBOSE >= 2 {
-                    print!("{}", header_line);
-                }
+// everything a request needs to be balanced, shared by all connections
+struct Balancer {
+    hosts: Arc<Hosts>,
+    // strategy picking the host for every request
+    strategy: Box<dyn Strategy>,
+    // cookie based sticky sessions, when enabled
+    sticky: Option<Sticky>,
+    // healthy fraction under which a priority group spills to the next one
+    failover_threshold: f64,
+    // responses of the load balancer itself
+    errors: ErrorResponses,
+    // how long to wait on a backend, forever when None
+    upstream_timeout: Option<Duration>,
+    // how long an idle client connection is kept open, zero to close it after
+    // every request
+    keepalive_timeout: Duration,
+}
 
-                let header: Vec<&str> = header_line.split(": ").collect();
-                match header[0] {
-                    "Content-Length" => {
-                        content_length = strip(header[1].to_string()).parse::<usize>().unwrap();
-                    }
-                    &_ => {}
-                }
+// serve the requests of a client connection until either side asks to close
+// it, or it goes idle for longer than the keep-alive timeout
+async fn load_balance(mut incoming: TcpStream, balancer: Arc<Balancer>) {
 
-                buf.append(&mut line);
+    let peer = incoming.peer_addr().ok();
+    let (incoming_read, mut incoming_write) = incoming.split();
 
-                if header_line == "\r\n" { break };
-            },
-            Err(_e) => println!("could not read")
-        }
+    // the reader outlives every request, it may already hold the next one
+    let mut client = BufReader::new(incoming_read);
 
-    }
+    // with keep-alive off the first request has all the time it needs
+    let idle_timeout = Some(balancer.keepalive_timeout).filter(|timeout| !timeout.is_zero());
 
-    // read the body
-    let mut body = vec![0; content_length];
+    loop {
+        // read the request head from the client, its body is streamed once a host is picked
+        let mut request: Message = match within(idle_timeout, read_request(&mut client)).await {
+            Some(Ok(request)) => request,
+            None | Some(Err(ParseError::Io(_))) => return,
+            Some(Err(e)) => {
+                if verbose() > 0 {
+                    println!("{} lb [WARN] invalid request: {}", now(), e);
+                }
+                let _ = incoming_write.write_all(&balancer.errors.response(400, None)).await;
+                return;
+            }
+        };
 
-    let _ = reader.read_exact(&mut body);
+        // a client expecting 100 Continue is answered by the load balancer once
+        // a backend is connected, rather than waiting on backends to send it
+        let expects_continue = request.expects_continue();
+        if expects_continue {
+            request.remove_header("Expect");
+        }
 
-    if VERBOSE >= 3 {
-        match String::from_utf8(body.clone()) {
-            Ok(decoded) => {
-                println!("{}", decoded);
-            },
-            Err(_e) => {
-                println!("unable to decode body");
-            }
+        if !forward(&request, expects_continue, &mut client, &mut incoming_write, peer, &balancer).await {
+            return;
         }
-        
     }
-
-    buf.append(&mut body);
-
-    buf
 }
 
+// balance a single request and relay the response, returning whether the
+// client connection can be used for another request
+async fn forward(request: &Message, expects_continue: bool, client: &mut BufReader<ReadHalf<'_>>, incoming: &mut WriteHalf<'_>, peer: Option<SocketAddr>, balancer: &Balancer) -> bool {
+
+    // a request that found its pooled connection closed by the host is sent
+    // again, once, on a new connection to the same host
+    let mut retry: Option<(String, Connection, Arc<Pool>)> = None;
+
+    // loop over healthy hosts until traffic is successfully routed
+    'hosts: loop {
+
+        // find the next healthy host in the current snapshot of the pool
+        let fresh = retry.is_some();
+        let picked = retry.take().or_else(|| {
+            let hosts = balancer.hosts.load();
+
+            // only route to the highest priority group that is healthy enough
+            let group = active_group(&hosts, balancer.failover_threshold);
+            let hosts_group = &hosts[group.clone()];
+
+            // stick to the host from the cookie, if it is still healthy
+            let sticky = balancer.sticky.as_ref().and_then(|sticky| sticky.pick(request, hosts_group));
+
+            sticky.or_else(|| balancer.strategy.pick(&Request { peer, message: request }, hosts_group))
+                .map(|index| {
+                    let host = &hosts_group[index];
+                    (host.url.clone(), Connection::open(host), host.pool.clone())
+                })
+        });
+
+        let (url, connection, pool) = match picked {
+            Some(picked) => picked,
+            None => {
+                println!("{} lb [WARN] no available hosts", now());
+                let _ = incoming.write_all(&balancer.errors.response(503, request.header("Accept"))).await;
+                return false;
+            }
+        };
 
+        // attempt to connect to the host, reusing an idle connection if there is
+        // one. a host that does not answer the handshake in time counts as down
+        let connect = async {
+            if fresh {
+                Ok((TcpStream::connect(&url).await?, false))
+            } else {
+                pool.checkout(&url).await
+            }
+        };
+        let checked_out = within(balancer.upstream_timeout, connect).await
+            .unwrap_or_else(|| Err(ErrorKind::TimedOut.into()));
 
-async fn load_balance(incoming: &mut TcpStream, hosts: Arc<Mutex<Vec<Host>>>, host_index: Arc<Mutex<usize>>) {
+        match checked_out {
 
-    // read the request from the client
-    let request: Vec<u8> = read_http(incoming); 
+            // if everything is ok, route traffic to the host and back to the client
+            Ok((mut host_stream, reused)) => {
+                // the host closing a reused connection before answering is only
+                // the idle connection racing its keep-alive timeout
+                let stale = |e: &io::Error| reused && request.idempotent() && matches!(request.body, Body::Length(0))
+                    && matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe);
+
+                // every read and write on the backend may take up to the upstream
+                // timeout, time spent waiting on the client does not count
+                let (host_read, host_write) = host_stream.split();
+                let mut host_write = Timed::new(host_write, balancer.upstream_timeout);
+
+                let mut body = Watched::new(&mut *client);
+                if expects_continue && !matches!(request.body, Body::Length(0)) && incoming.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.is_err() {
+                    return false;
+                }
+                let sent = async {
+                    host_write.write_all(&request.head).await?;
+                    copy_body(&mut body, &mut host_write, &request.body).await
+                }.await;
+
+                if let Err(e) = &sent {
+                    // the client went away halfway through its body, that is
+                    // neither the backend's fault nor anyone left to answer
+                    if body.ended() && e.kind() != ErrorKind::InvalidData {
+                        if verbose() > 0 {
+                            println!("{} lb [WARN] client aborted {} {} -> {}: {}", now(), request.method(), request.path(), url, e);
+                        }
+                        return false;
+                    }
+                    if stale(e) {
+                        if verbose() > 0 {
+                            println!("{} lb [WARN] pooled connection to {} was closed, retrying: {}", now(), url, e);
+                        }
+                        retry = Some((url, connection, pool));
+                        continue 'hosts;
+                    }
+                }
 
-    // find the next healthy host
-    let mut host_index_lock = host_index.lock().await;
-    let mut hosts_lock = hosts.lock().await;
+                // a malformed request body is the client's fault, anything else
+                // is the backend failing
+                let failed = match sent {
+                    Ok(()) => None,
+                    Err(e) if e.kind() == ErrorKind::InvalidData => Some((400, e.to_string())),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => Some((504, e.to_string())),
+                    Err(e) => Some((502, e.to_string())),
+                };
+                if let Some((status, e)) = failed {
+                    // how long the upload took is up to the client, only the
+                    // failure counts against the backend
+                    if status != 400 {
+                        connection.fail(Duration::ZERO);
+                    }
+                    if verbose() > 0 {
+                        println!("{} lb [WARN] {} for {} {} -> {}: {}", now(), status, request.method(), request.path(), url, e);
+                    }
+                    let _ = incoming.write_all(&balancer.errors.response(status, request.header("Accept"))).await;
+                    return false;
+                }
 
-    // loop until traffic is successfully routed
-    let mut failures: usize;
-    loop {
+                // the latency of the backend is the time to the response head,
+                // from when it has the whole request
+                let started = Instant::now();
+
+                // relay interim responses such as 100 Continue until the final one
+                let mut host_reader = BufReader::new(Timed::new(host_read, balancer.upstream_timeout));
+                let mut interim = false;
+                let mut response: Message = loop {
+                    let failed = match read_response(&mut host_reader, request.method()).await {
+                        Ok(response) if response.status().is_some_and(|status| (100..200).contains(&status) && status != 101) => {
+                            if incoming.write_all(&response.head).await.is_err() {
+                                return false;
+                            }
+                            interim = true;
+                            continue;
+                        },
+                        Ok(response) => break response,
+                        Err(ParseError::Io(e)) if !interim && stale(&e) => {
+                            if verbose() > 0 {
+                                println!("{} lb [WARN] pooled connection to {} was closed, retrying: {}", now(), url, e);
+                            }
+                            retry = Some((url, connection, pool));
+                            continue 'hosts;
+                        },
+                        Err(ParseError::Io(e)) if e.kind() == ErrorKind::TimedOut => (504, e.to_string()),
+                        Err(e) => (502, e.to_string()),
+                    };
+
+                    // nothing of the response reached the client yet, so it can
+                    // still be told what went wrong
+                    let (status, e) = failed;
+                    connection.fail(started.elapsed());
+                    if verbose() > 0 {
+                        println!("{} lb [WARN] {} for {} {} -> {}: {}", now(), status, request.method(), request.path(), url, e);
+                    }
+                    let _ = incoming.write_all(&balancer.errors.response(status, request.header("Accept"))).await;
+                    return false;
+                };
+                connection.observe(started.elapsed());
+
+                // after a 101 the connection belongs to the upgraded protocol: relay
+                // it both ways, starting with what the client already sent, until
+                // both sides are done. it may sit idle as long as the protocol likes
+                if response.status() == Some(101) {
+                    if incoming.write_all(&response.head).await.is_err() {
+                        return false;
+                    }
+                    host_reader.get_mut().set_timeout(None);
+                    host_write.set_timeout(None);
+
+                    let upstream = async {
+                        tokio::io::copy_buf(client, &mut host_write).await?;
+                        host_write.shutdown().await
+                    };
+                    let downstream = async {
+                        tokio::io::copy_buf(&mut host_reader, incoming).await?;
+                        incoming.shutdown().await
+                    };
+                    let _ = tokio::join!(upstream, downstream);
+
+                    println!("{} lb [INFO] {} {} -> {} (upgraded)", now(), request.method(), request.path(), url);
+                    return false;
+                }
 
-        // find a healthy host to route to
-        failures = 0;
-        while failures < hosts_lock.len() {
+                // the end of a close delimited body can only be told to the
+                // client by closing its connection as well
+                let delimited = !matches!(response.body, Body::Close);
 
-            // increment over the available *hosts_lock, round-robin style
-            *host_index_lock = (*host_index_lock + 1) % (*hosts_lock).len();
+                // the backend connection can be pooled when the backend keeps it
+                // open and the end of the response body is known
+                let reusable = response.keep_alive() && delimited;
 
-            // if the host is healthy, bail out1
-            if hosts_lock[*host_index_lock].healthy {
-                break;
-            }
-                
-            if VERBOSE > 0 { 
-                println!("{} lb [WARN] {} is unhealthy", now(), hosts_lock[*host_index_lock].url);
-            }
-            failures += 1;
-        }
+                if let Some(sticky) = &balancer.sticky {
+                    sticky.set_cookie(request, &mut response, &url);
+                }
 
-        if failures >= hosts_lock.len() { 
-            break; 
-        }
+                // let the client know whether the connection stays open, when
+                // that differs from what the backend told it
+                let keep_alive = !balancer.keepalive_timeout.is_zero() && request.keep_alive() && response.keep_alive() && delimited;
+                if !keep_alive && response.keep_alive() {
+                    response.insert_header("Connection", "close");
+                } else if keep_alive && request.version() != "HTTP/1.1" && response.header("Connection").is_none() {
+                    response.insert_header("Connection", "keep-alive");
+                }
 
-        // attempt to connect to the host
-        match TcpStream::connect(&hosts_lock[*host_index_lock].url) {
+                // once the head is out, a failing backend can only be reported
+                // by closing the connection before the end of the body
+                if incoming.write_all(&response.head).await.is_err()
+                    || copy_body(&mut host_reader, incoming, &response.body).await.is_err() {
+                    return false;
+                }
 
-            // if everything is ok, route traffic to the host and back to the client
-            Ok(mut host_stream) => {
-                let _ = host_stream.write_all(&request);
-                let response: Vec<u8> = read_http(&mut host_stream);
-                let _ = incoming.write_all(&response);
-                break
+                let drained = host_reader.buffer().is_empty();
+                drop(host_reader);
+                if reusable && drained {
+                    pool.checkin(host_stream);
+                }
+                println!("{} lb [INFO] {} {} -> {}", now(), request.method(), request.path(), url);
+                return keep_alive;
             },
 
             // if connecting fails, mark the host as unhealthy and loop to find another one
-            Err(_e) => {
-                if VERBOSE > 0 { 
-                    println!("{} lb [WARN] marking unhealthy: {}", now(), hosts_lock[*host_index_lock].url);
+            Err(e) => {
+                if verbose() > 0 { 
+                    println!("{} lb [WARN] marking unhealthy: {}: {}", now(), url, e);
                 }
-                hosts_lock[*host_index_lock].healthy = false;
+                update_health(&balancer.hosts, &[(&url, false)]);
             }
         }
     }
-    
-
-    if failures >= hosts_lock.len() { 
-        println!("{} lb [WARN] no available hosts", now());
-    } else {
-        println!("{} lb [INFO] {}", now(),  hosts_lock[*host_index_lock].url);
-    }
 }
 
-struct Host {
-    url: String,
-    healthy: bool,
+// run `future` for at most `timeout`, None when it took longer
+async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
 }
 
-async fn healthy(host: &Host) -> bool {
-    let response = reqwest::get(format!("http://{}", host.url)).await;
-
-
-    match response {
-        Ok(resp) => {
-            return resp.status().is_success();
-        },
-        Err(_e) => {
-            return false;
+fn resolve_config(args: &ConfigArgs) -> Config {
+    match args.resolve() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     }
 }
 
-async fn check_health(hosts: &Arc<Mutex<Vec<Host>>>) {
-            
-    let mut hosts_lock = hosts.lock().await;
-
-    for host in &mut *hosts_lock {
-        host.healthy = healthy(&host).await;
-        if VERBOSE > 0 {
-                
-            match host.healthy {
-                true => println!("{} lb [INFO] {} is healthy", now(), host.url),
-                false => println!("{} lb [WARN] {} is unhealthy", now(), host.url),
-            }
-        }
+fn check_config(config: &Config) {
+    println!("listen: {}", config.listen);
+    println!("healthcheck_period_millis: {}", config.healthcheck_period_millis);
+    println!("verbose: {}", config.verbose);
+    println!("keepalive_timeout_millis: {}", config.keepalive_timeout_millis);
+    println!("pool_max_idle: {}", config.pool_max_idle);
+    println!("pool_idle_timeout_millis: {}", config.pool_idle_timeout_millis);
+    println!("upstream_timeout_millis: {}", config.upstream_timeout_millis);
+    println!("strategy: {}", config.strategy);
+    if config.strategy == "consistent_hash" {
+        println!("consistent_hash.key: {}", config.consistent_hash.key);
+        println!("consistent_hash.virtual_nodes: {}", config.consistent_hash.virtual_nodes);
+    }
+    if config.strategy == "maglev" {
+        println!("maglev.key: {}", config.maglev.key);
+        println!("maglev.table_size: {}", config.maglev.table_size);
+    }
+    if config.strategy == "rendezvous" {
+        println!("rendezvous.key: {}", config.rendezvous.key);
+    }
+    println!("failover_threshold: {}", config.failover_threshold);
+    println!("slow_start_millis: {}", config.slow_start_millis);
+    if let Some(cookie) = &config.sticky_cookie {
+        println!("sticky_cookie: {}", cookie);
+    }
+    println!("errors.retry_after_secs: {}", config.errors.retry_after_secs);
+    let mut bodies: Vec<_> = config.errors.bodies.iter().collect();
+    bodies.sort();
+    for (status, body) in bodies {
+        println!("errors.bodies.{}: {:?}", status, body);
+    }
+    println!("watch_config: {}", config.watch_config);
+    println!("backends:");
+    for backend in &config.backends {
+        println!("  {} weight={} priority={}", backend.address, backend.weight, backend.priority);
     }
-                
 }
 
-async fn initialize_hosts(host_urls: Vec<&str>) -> Vec<Host> {
-    let mut hosts: Vec<Host> = Vec::new();
-    for host_url in host_urls {
-        let host = Host {
-            url: host_url.into(),
-            healthy: false,
-        };
-        hosts.push(host)
+async fn list_backends(config: &Config) {
+    let hosts = initialize_hosts(config).await;
+
+    for host in &hosts {
+        let status = if healthy(host).await { "healthy" } else { "unhealthy" };
+        println!("{} {}", host.url, status);
     }
-    hosts
-    
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn run(args: ConfigArgs) -> Result<(), Box<dyn Error>> {
 
-    // load balancer url
-    let endpoint = "127.0.0.1:9876";
+    // kept to tell which settings a reload cannot apply
+    let config = Arc::new(resolve_config(&args));
 
-    let hosts_urls = vec![
-        "127.0.0.1:8080",
-        "127.0.0.1:8081",
-        "127.0.0.1:8082",
-    ];
+    VERBOSE.store(config.verbose, Ordering::Relaxed);
+
+    // load balancer url
+    let endpoint = config.listen.clone();
+    let healthcheck_period_millis = config.healthcheck_period_millis;
 
     // initialize hosts 
-    let hosts = Arc::new(Mutex::new(initialize_hosts(hosts_urls).await));
+    let hosts = Arc::new(Hosts::from_pointee(initialize_hosts(&config).await));
     
     // initialize the health check list
     let hosts_checkhealth = hosts.clone();
@@ -230,27 +419,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
         loop {
             check_health(&hosts_checkhealth).await;
 
-            sleep(Duration::from_millis(HEALTHCHECK_PERIOD_MILLIS)).await;
+            sleep(Duration::from_millis(healthcheck_period_millis)).await;
         }
 
     });
 
+    // reload the backend pool on SIGHUP, and on config changes if asked to
+    let args = Arc::new(args);
+    reload::reload_on_sighup(args.clone(), config.clone(), hosts.clone())?;
+    if config.watch_config {
+        reload::reload_on_change(args.clone(), config.clone(), hosts.clone());
+    }
 
     // listen on the load balancer endpoint
-    let listener = TcpListener::bind(&endpoint).unwrap();
-
-    // index for host
-    let host_index: Arc<Mutex<usize>> = Arc::new(Mutex::new(0 as usize));
+    let listener = TcpListener::bind(&endpoint).await?;
+
+    let balancer = Arc::new(Balancer {
+        hosts: hosts.clone(),
+        strategy: strategy::from_config(&config),
+        sticky: config.sticky_cookie.as_deref().map(Sticky::new),
+        failover_threshold: config.failover_threshold,
+        keepalive_timeout: Duration::from_millis(config.keepalive_timeout_millis),
+        errors: ErrorResponses::new(&config.errors),
+        upstream_timeout: Some(Duration::from_millis(config.upstream_timeout_millis)).filter(|timeout| !timeout.is_zero()),
+    });
 
-    for incoming in listener.incoming() {
-        match incoming {
-            Ok(mut incoming_stream) => {
+    loop {
+        match listener.accept().await {
+            Ok((incoming_stream, _)) => {
 
-                let hosts_incoming = hosts.clone();
-                let host_index_incoming = host_index.clone();
+                let balancer_incoming = balancer.clone();
 
                 tokio::spawn(async move { 
-                    load_balance(&mut incoming_stream, hosts_incoming, host_index_incoming).await
+                    load_balance(incoming_stream, balancer_incoming).await
                     }
                 );
 
@@ -261,6 +462,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Run(args) => run(args).await,
+        Command::CheckConfig(args) => {
+            check_config(&resolve_config(&args));
+            Ok(())
+        },
+        Command::ListBackends(args) => {
+            list_backends(&resolve_config(&args)).await;
+            Ok(())
+        },
+    }
 
 }
@@ -0,0 +1,134 @@
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use tokio::net::TcpStream;
+
+// idle keep-alive connections to a host, so requests can skip the tcp handshake
+pub struct Pool {
+    // connections and when they were checked in, most recent last
+    idle: Mutex<Vec<(TcpStream, Instant)>>,
+    // most idle connections kept, no pooling when 0
+    max_idle: usize,
+    // how long a connection may sit idle before it is closed
+    idle_timeout: Duration,
+}
+
+impl Pool {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Pool {
+        Pool { idle: Mutex::new(vec![]), max_idle, idle_timeout }
+    }
+
+    // an idle connection to `url` that is still usable, or a new one, and
+    // whether it was reused
+    pub async fn checkout(&self, url: &str) -> io::Result<(TcpStream, bool)> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+
+            match idle {
+                Some((stream, since)) if since.elapsed() < self.idle_timeout && usable(&stream) => return Ok((stream, true)),
+                // expired, closed by the host, or left in a bad state, drop it
+                Some(_) => continue,
+                None => return Ok((TcpStream::connect(url).await?, false)),
+            }
+        }
+    }
+
+    // hand back a connection whose last response was read in full, for reuse
+    pub fn checkin(&self, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+
+        idle.retain(|(_, since)| since.elapsed() < self.idle_timeout);
+        if idle.len() < self.max_idle {
+            idle.push((stream, Instant::now()));
+        }
+    }
+}
+
+// an idle connection is usable when the host has neither closed it nor sent
+// anything on it since the last response: a peek polled once is only pending
+// while there is nothing to read
+fn usable(stream: &TcpStream) -> bool {
+    stream.peek(&mut [0; 1]).now_or_never().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn idle(pool: &Pool) -> usize {
+        pool.idle.lock().unwrap().len()
+    }
+
+    async fn listener() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = listener.local_addr().unwrap().to_string();
+        (listener, url)
+    }
+
+    #[tokio::test]
+    async fn reuses_idle_connections() {
+        let (listener, url) = listener().await;
+        let pool = Pool::new(2, Duration::from_secs(60));
+
+        let stream = pool.checkout(&url).await.unwrap().0;
+        let local = stream.local_addr().unwrap();
+        let _accepted = listener.accept().await.unwrap();
+        pool.checkin(stream);
+
+        let (stream, reused) = pool.checkout(&url).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap(), local);
+        assert!(reused);
+        assert_eq!(idle(&pool), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_at_most_max_idle() {
+        let (_listener, url) = listener().await;
+        let pool = Pool::new(1, Duration::from_secs(60));
+
+        let first = pool.checkout(&url).await.unwrap().0;
+        let second = pool.checkout(&url).await.unwrap().0;
+        pool.checkin(first);
+        pool.checkin(second);
+
+        assert_eq!(idle(&pool), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_expired_connections() {
+        let (_listener, url) = listener().await;
+        let pool = Pool::new(2, Duration::ZERO);
+
+        let stream = pool.checkout(&url).await.unwrap().0;
+        let local = stream.local_addr().unwrap();
+        pool.checkin(stream);
+
+        assert_ne!(pool.checkout(&url).await.unwrap().0.local_addr().unwrap(), local);
+    }
+
+    #[tokio::test]
+    async fn validates_connections_on_checkout() {
+        let (listener, url) = listener().await;
+        let pool = Pool::new(2, Duration::from_secs(60));
+
+        // one connection closed by the host, one with unexpected data on it
+        let closed = pool.checkout(&url).await.unwrap().0;
+        drop(listener.accept().await.unwrap());
+        let chatty = pool.checkout(&url).await.unwrap().0;
+        listener.accept().await.unwrap().0.write_all(b"HTTP/1.1 408 Request Timeout\r\n\r\n").await.unwrap();
+
+        let stale = [closed.local_addr().unwrap(), chatty.local_addr().unwrap()];
+        pool.checkin(closed);
+        pool.checkin(chatty);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let stream = pool.checkout(&url).await.unwrap().0;
+        assert!(!stale.contains(&stream.local_addr().unwrap()));
+        assert_eq!(idle(&pool), 0);
+    }
+}
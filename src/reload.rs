@@ -0,0 +1,167 @@
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use futures::future::join_all;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Duration};
+
+use crate::cli::ConfigArgs;
+use crate::config::Config;
+use crate::host::{healthy, initialize_hosts, Host, Hosts};
+use crate::{now, VERBOSE};
+
+// how often the config file is checked for changes when `watch_config` is set
+const CONFIG_WATCH_PERIOD_MILLIS: u64 = 2 * 1000;
+
+// re-read the configuration and swap in the new backend pool
+//
+// backends that stay in the pool keep their health state, connection count and latency,
+// new ones are probed before they are swapped in so they can take traffic right
+// away. requests already being proxied hold on to the address they were routed
+// to, so they finish on their original backend.
+//
+// connection pools start out empty so that changed pool settings apply. settings
+// read once at startup are compared with `running`, and a warning is logged for
+// each one that changed.
+pub async fn reload(args: &ConfigArgs, running: &Config, hosts: &Hosts) {
+
+    let config = match args.resolve() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{} lb [WARN] reload failed, keeping the current backends: {}", now(), e);
+            return;
+        }
+    };
+
+    VERBOSE.store(config.verbose, Ordering::Relaxed);
+
+    if config.listen != running.listen {
+        println!("{} lb [WARN] listen address changed to {}, a restart is required to apply it", now(), config.listen);
+    }
+
+    for key in restart_required(running, &config) {
+        println!("{} lb [WARN] {} changed, a restart is required to apply it", now(), key);
+    }
+
+    let known: Vec<String> = hosts.load().iter().map(|host| host.url.clone()).collect();
+
+    // probe the new backends all at once, while requests keep using the
+    // current pool
+    let mut new_hosts = initialize_hosts(&config).await;
+    let added: Vec<&mut Host> = new_hosts.iter_mut().filter(|host| !known.contains(&host.url)).collect();
+    let results = join_all(added.iter().map(|host| healthy(host))).await;
+    for (host, healthy) in added.into_iter().zip(results) {
+        host.set_healthy(healthy);
+        println!("{} lb [INFO] adding {}", now(), host.url);
+    }
+
+    // carry over the state of the backends we keep, from the pool as it is
+    // when swapping, so health changes made meanwhile are not lost
+    let old_hosts = hosts.rcu(|current| {
+        let mut hosts = new_hosts.clone();
+        for host in hosts.iter_mut() {
+            if let Some(old) = current.iter().find(|old| old.url == host.url) {
+                host.healthy = old.healthy;
+                host.healthy_since = old.healthy_since;
+                host.active = old.active.clone();
+                host.latency = old.latency.clone();
+            }
+        }
+        hosts
+    });
+
+    for old in old_hosts.iter().filter(|old| !new_hosts.iter().any(|host| host.url == old.url)) {
+        println!("{} lb [INFO] removing {}", now(), old.url);
+    }
+
+    println!("{} lb [INFO] reloaded {} backends", now(), new_hosts.len());
+}
+
+// keys of the settings that differ between `running` and `config` but are only
+// read at startup, besides listen
+fn restart_required(running: &Config, config: &Config) -> Vec<&'static str> {
+    let changed = [
+        ("healthcheck_period_millis", running.healthcheck_period_millis != config.healthcheck_period_millis),
+        ("keepalive_timeout_millis", running.keepalive_timeout_millis != config.keepalive_timeout_millis),
+        ("upstream_timeout_millis", running.upstream_timeout_millis != config.upstream_timeout_millis),
+        ("strategy", running.strategy != config.strategy),
+        ("consistent_hash", running.consistent_hash != config.consistent_hash),
+        ("maglev", running.maglev != config.maglev),
+        ("rendezvous", running.rendezvous != config.rendezvous),
+        ("failover_threshold", running.failover_threshold != config.failover_threshold),
+        ("sticky_cookie", running.sticky_cookie != config.sticky_cookie),
+        ("errors", running.errors != config.errors),
+    ];
+
+    changed.into_iter().filter(|(_, changed)| *changed).map(|(key, _)| key).collect()
+}
+
+// reload on every SIGHUP
+pub fn reload_on_sighup(args: Arc<ConfigArgs>, running: Arc<Config>, hosts: Arc<Hosts>) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            println!("{} lb [INFO] received SIGHUP, reloading {}", now(), args.config.display());
+            reload(&args, &running, &hosts).await;
+        }
+    });
+
+    Ok(())
+}
+
+// reload whenever the modification time of the config file changes
+pub fn reload_on_change(args: Arc<ConfigArgs>, running: Arc<Config>, hosts: Arc<Hosts>) {
+    tokio::spawn(async move {
+        let modified = |args: &ConfigArgs| -> Option<SystemTime> {
+            fs::metadata(&args.config).and_then(|metadata| metadata.modified()).ok()
+        };
+
+        let mut last_modified = modified(&args);
+
+        loop {
+            sleep(Duration::from_millis(CONFIG_WATCH_PERIOD_MILLIS)).await;
+
+            let current = modified(&args);
+            if current != last_modified {
+                last_modified = current;
+                println!("{} lb [INFO] {} changed, reloading", now(), args.config.display());
+                reload(&args, &running, &hosts).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &str) -> Config {
+        toml::from_str(&format!(
+            "listen = \"127.0.0.1:8000\"\n{}\n\n[[backends]]\naddress = \"10.0.0.1:8080\"\n", extra
+        )).unwrap()
+    }
+
+    #[test]
+    fn names_the_settings_that_need_a_restart() {
+        let running = parse("strategy = \"round_robin\"");
+
+        // backends and verbose are applied on reload
+        let config = toml::from_str::<Config>(
+            "listen = \"127.0.0.1:8000\"\nverbose = 3\nstrategy = \"round_robin\"\n[[backends]]\naddress = \"10.0.0.2:8080\"\n"
+        ).unwrap();
+        assert!(restart_required(&running, &config).is_empty());
+
+        let config = parse(concat!(
+            "strategy = \"maglev\"\nsticky_cookie = \"lb\"\nfailover_threshold = 0.7\n",
+            "upstream_timeout_millis = 1000\nkeepalive_timeout_millis = 0\n",
+            "[errors.bodies]\n503 = \"down for maintenance\"",
+        ));
+        assert_eq!(restart_required(&running, &config), [
+            "keepalive_timeout_millis", "upstream_timeout_millis", "strategy",
+            "failover_threshold", "sticky_cookie", "errors",
+        ]);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::host::Host;
+use crate::http::Message;
+use crate::strategy::hash;
+
+// cookie based sticky sessions: the response of the first request of a client
+// sets a cookie naming the host it was routed to, and later requests carrying
+// that cookie go back to the same host as long as it is healthy
+pub struct Sticky {
+    cookie: String,
+}
+
+// what the cookie holds for a host, so backend addresses are not leaked to clients
+fn host_id(url: &str) -> String {
+    format!("{:016x}", hash(url.as_bytes()))
+}
+
+impl Sticky {
+    pub fn new(cookie: &str) -> Sticky {
+        Sticky { cookie: cookie.to_string() }
+    }
+
+    // index of the healthy host named by the cookie of the request, if any
+    pub fn pick(&self, request: &Message, hosts: &[Host]) -> Option<usize> {
+        let id = request.cookie(&self.cookie)?;
+        hosts.iter().position(|host| host.healthy && host_id(&host.url) == id)
+    }
+
+    // have the client stick to the host at `url`, unless its cookie already says so
+    pub fn set_cookie(&self, request: &Message, response: &mut Message, url: &str) {
+        let id = host_id(url);
+        if request.cookie(&self.cookie) != Some(id.as_str()) {
+            response.insert_header("Set-Cookie", &format!("{}={}; Path=/; HttpOnly", self.cookie, id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Body;
+    use crate::strategy::tests::{hosts, message};
+
+    fn response() -> Message {
+        Message {
+            head: b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            start_line: "HTTP/1.1 200 OK".to_string(),
+            headers: vec![],
+            body: Body::Length(0),
+        }
+    }
+
+    fn with_cookie(url: &str) -> Message {
+        message("/", &[("Cookie", &format!("theme=dark; lb={}", host_id(url)))])
+    }
+
+    #[test]
+    fn pins_requests_to_the_host_in_the_cookie() {
+        let pool = hosts(&[1, 1, 1]);
+        let sticky = Sticky::new("lb");
+
+        assert_eq!(sticky.pick(&with_cookie(&pool[2].url), &pool), Some(2));
+        assert_eq!(sticky.pick(&message("/", &[]), &pool), None);
+        assert_eq!(sticky.pick(&message("/", &[("Cookie", "lb=0123456789abcdef")]), &pool), None);
+    }
+
+    #[test]
+    fn falls_back_when_the_pinned_host_cannot_take_it() {
+        let mut pool = hosts(&[1, 1, 1]);
+        let sticky = Sticky::new("lb");
+        let request = with_cookie(&pool[1].url);
+
+        // not in the active group
+        assert_eq!(sticky.pick(&request, &pool[2..]), None);
+
+        pool[1].healthy = false;
+        assert_eq!(sticky.pick(&request, &pool), None);
+    }
+
+    #[test]
+    fn sets_the_cookie_only_when_it_changes() {
+        let pool = hosts(&[1, 1]);
+        let sticky = Sticky::new("lb");
+
+        let mut fresh = response();
+        sticky.set_cookie(&message("/", &[]), &mut fresh, &pool[0].url);
+        let expected = format!("lb={}; Path=/; HttpOnly", host_id(&pool[0].url));
+        assert_eq!(fresh.header("Set-Cookie"), Some(expected.as_str()));
+        assert!(String::from_utf8(fresh.head).unwrap().ends_with(&format!("Set-Cookie: {}\r\n\r\n", expected)));
+
+        let mut same = response();
+        sticky.set_cookie(&with_cookie(&pool[0].url), &mut same, &pool[0].url);
+        assert_eq!(same.header("Set-Cookie"), None);
+
+        // the client moved to another host
+        let mut moved = response();
+        sticky.set_cookie(&with_cookie(&pool[0].url), &mut moved, &pool[1].url);
+        assert_eq!(moved.header("Set-Cookie"), Some(format!("lb={}; Path=/; HttpOnly", host_id(&pool[1].url)).as_str()));
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::config;
+use crate::host::Host;
+use crate::strategy::{hash, HashKey, Request, Strategy};
+
+// ketama style consistent hashing: every host is placed on a ring of u64 at
+// `virtual_nodes * weight` points, and a request goes to the first healthy host
+// clockwise from the hash of its key. adding or removing a host, or a host going
+// unhealthy, only moves the keys that land next to its points.
+pub struct ConsistentHash {
+    key: HashKey,
+    virtual_nodes: u32,
+    ring: ArcSwap<Ring>,
+}
+
+#[derive(Default)]
+struct Ring {
+    // url and weight of the hosts the ring was built for
+    hosts: Vec<(String, u32)>,
+    // sorted points, and the index of the host they belong to
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn build(hosts: &[Host], virtual_nodes: u32) -> Ring {
+        let mut points = Vec::new();
+
+        for (index, host) in hosts.iter().enumerate() {
+            for node in 0..virtual_nodes as u64 * host.weight as u64 {
+                points.push((hash(format!("{}-{}", host.url, node).as_bytes()), index));
+            }
+        }
+        points.sort_unstable();
+
+        Ring {
+            hosts: hosts.iter().map(|host| (host.url.clone(), host.weight)).collect(),
+            points,
+        }
+    }
+
+    // index of the host `key` goes to, walking clockwise from it, wrapping
+    // around, until a healthy host
+    fn lookup(&self, key: u64, hosts: &[Host]) -> Option<usize> {
+        let start = self.points.partition_point(|(point, _)| *point < key);
+
+        self.points[start..].iter()
+            .chain(&self.points[..start])
+            .map(|(_, index)| *index)
+            .find(|index| hosts[*index].healthy)
+    }
+
+    fn built_for(&self, hosts: &[Host]) -> bool {
+        self.hosts.len() == hosts.len()
+            && self.hosts.iter().zip(hosts).all(|((url, weight), host)| *url == host.url && *weight == host.weight)
+    }
+}
+
+impl ConsistentHash {
+    pub fn new(config: &config::ConsistentHash) -> ConsistentHash {
+        ConsistentHash {
+            key: HashKey::parse(&config.key).expect("hash key is validated with the config"),
+            virtual_nodes: config.virtual_nodes,
+            ring: ArcSwap::from_pointee(Ring::default()),
+        }
+    }
+}
+
+impl Strategy for ConsistentHash {
+    fn pick(&self, request: &Request, hosts: &[Host]) -> Option<usize> {
+        let mut ring = self.ring.load_full();
+
+        // the pool changed since the last request, rebuild the ring. requests
+        // racing to rebuild it all build the same one
+        if !ring.built_for(hosts) {
+            ring = Arc::new(Ring::build(hosts, self.virtual_nodes));
+            self.ring.store(ring.clone());
+        }
+
+        ring.lookup(hash(self.key.extract(request).as_bytes()), hosts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tests::hosts;
+
+    const VIRTUAL_NODES: u32 = 160;
+    const KEYS: u64 = 10000;
+
+    // url of the host every key goes to
+    fn owners(hosts: &[Host]) -> Vec<Option<String>> {
+        let ring = Ring::build(hosts, VIRTUAL_NODES);
+        (0..KEYS)
+            .map(|key| ring.lookup(hash(key.to_string().as_bytes()), hosts).map(|index| hosts[index].url.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn spreads_by_weight() {
+        let pool = hosts(&[1, 3]);
+
+        let owners = owners(&pool);
+        let heavy = owners.iter().filter(|owner| owner.as_deref() == Some(pool[1].url.as_str())).count() as u64;
+        assert!(heavy.abs_diff(KEYS * 3 / 4) < KEYS / 20, "{} of {} keys on the heavy host", heavy, KEYS);
+    }
+
+    #[test]
+    fn unhealthy_host_only_moves_its_own_keys() {
+        let mut pool = hosts(&[1; 10]);
+        let before = owners(&pool);
+
+        pool[3].healthy = false;
+        let after = owners(&pool);
+
+        for (before, after) in before.iter().zip(&after) {
+            if *before != Some(pool[3].url.clone()) {
+                assert_eq!(before, after);
+            }
+        }
+        assert!(after.iter().all(|owner| *owner != Some(pool[3].url.clone())));
+    }
+
+    #[test]
+    fn removing_a_host_only_moves_its_own_keys() {
+        let pool = hosts(&[1; 10]);
+        let before = owners(&pool);
+        let removed = pool[3].url.clone();
+
+        let smaller: Vec<Host> = pool.into_iter().filter(|host| host.url != removed).collect();
+        let after = owners(&smaller);
+
+        let moved = before.iter().zip(&after).filter(|(before, after)| before != after).count();
+        let owned = before.iter().filter(|owner| owner.as_deref() == Some(removed.as_str())).count();
+        assert_eq!(moved, owned);
+    }
+
+    #[test]
+    fn adding_a_host_moves_about_its_share() {
+        let before = owners(&hosts(&[1; 10]));
+        let after = owners(&hosts(&[1; 11]));
+
+        // only keys taken by the new host move, about 1/11 of them
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(before, after)| before != after).collect();
+        assert!(moved.iter().all(|(_, after)| after.as_deref() == Some("10.0.0.10:8080")));
+        assert!((moved.len() as u64) < KEYS * 12 / 100, "{} of {} keys moved", moved.len(), KEYS);
+    }
+
+    #[test]
+    fn no_healthy_host() {
+        let mut pool = hosts(&[1; 3]);
+        for host in pool.iter_mut() {
+            host.healthy = false;
+        }
+        assert!(owners(&pool).iter().all(Option::is_none));
+    }
+}
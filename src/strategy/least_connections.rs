@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::host::Host;
+use crate::strategy::{Request, Strategy};
+
+// pick the healthy host with the fewest in-flight requests relative to its weight,
+// scanning from the one after the last pick so ties are spread round robin
+#[derive(Default)]
+pub struct LeastConnections {
+    last: AtomicUsize,
+}
+
+impl Strategy for LeastConnections {
+    fn pick(&self, _request: &Request, hosts: &[Host]) -> Option<usize> {
+        let last = self.last.load(Ordering::Relaxed);
+        let mut best: Option<usize> = None;
+
+        for offset in 1..=hosts.len() {
+            let index = (last + offset) % hosts.len();
+            if !hosts[index].healthy {
+                continue;
+            }
+
+            // compare active / weight without dividing
+            if best.is_none_or(|best| {
+                hosts[index].active() as f64 * hosts[best].effective_weight()
+                    < hosts[best].active() as f64 * hosts[index].effective_weight()
+            }) {
+                best = Some(index);
+            }
+        }
+
+        let best = best?;
+        self.last.store(best, Ordering::Relaxed);
+        Some(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::Connection;
+    use crate::strategy::tests::{hosts, message};
+
+    fn picks(strategy: &LeastConnections, hosts: &[Host], count: usize) -> Vec<Option<usize>> {
+        let message = message("/", &[]);
+        let request = Request { peer: None, message: &message };
+
+        (0..count).map(|_| strategy.pick(&request, hosts)).collect()
+    }
+
+    #[test]
+    fn picks_the_fewest_connections_relative_to_weight() {
+        let pool = hosts(&[1, 1, 1]);
+        let _active: Vec<Connection> = [0, 0, 2].iter().map(|index| Connection::open(&pool[*index])).collect();
+        assert_eq!(picks(&LeastConnections::default(), &pool, 1), [Some(1)]);
+
+        // two connections on a host of weight 4 weigh less than one on weight 1
+        let pool = hosts(&[1, 4]);
+        let _active: Vec<Connection> = [0, 1, 1].iter().map(|index| Connection::open(&pool[*index])).collect();
+        assert_eq!(picks(&LeastConnections::default(), &pool, 1), [Some(1)]);
+    }
+
+    #[test]
+    fn spreads_ties_round_robin() {
+        let pool = hosts(&[1, 1, 1]);
+        assert_eq!(picks(&LeastConnections::default(), &pool, 4), [Some(1), Some(2), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn skips_unhealthy_hosts() {
+        let mut pool = hosts(&[1, 1, 1]);
+        pool[1].healthy = false;
+        assert_eq!(picks(&LeastConnections::default(), &pool, 3), [Some(2), Some(0), Some(2)]);
+
+        for host in pool.iter_mut() {
+            host.healthy = false;
+        }
+        assert_eq!(picks(&LeastConnections::default(), &pool, 1), [None]);
+        assert_eq!(picks(&LeastConnections::default(), &[], 1), [None]);
+    }
+}
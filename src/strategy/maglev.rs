@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::config;
+use crate::host::Host;
+use crate::strategy::{hash, HashKey, Request, Strategy};
+
+// maglev hashing, from google's maglev load balancer paper: every healthy host
+// fills slots of a prime sized lookup table in the order of its own permutation
+// of the slots, taking `weight` slots per turn. a request goes to the host in
+// the slot of the hash of its key, so picking is O(1), every host owns a share
+// of the table proportional to its weight, and when the pool or its health
+// changes only a small fraction of the slots change hands.
+pub struct Maglev {
+    key: HashKey,
+    table_size: usize,
+    table: ArcSwap<Table>,
+}
+
+#[derive(Default)]
+struct Table {
+    // url, weight and health of the hosts the table was built for
+    hosts: Vec<(String, u32, bool)>,
+    // index of the host owning every slot, empty when no host is healthy
+    slots: Vec<usize>,
+}
+
+impl Table {
+    fn build(hosts: &[Host], size: usize) -> Table {
+        let healthy: Vec<usize> = (0..hosts.len()).filter(|index| hosts[*index].healthy).collect();
+
+        // every host walks the table starting at `offset`, `skip` slots at a
+        // time. the size is prime, so every walk visits every slot
+        let walks: Vec<(usize, usize)> = healthy.iter()
+            .map(|index| {
+                let url = hosts[*index].url.as_bytes();
+                let offset = hash(url) % size as u64;
+                let skip = hash(&[url, b"#skip"].concat()) % (size as u64 - 1) + 1;
+                (offset as usize, skip as usize)
+            })
+            .collect();
+
+        let mut slots: Vec<Option<usize>> = vec![None; size];
+        let mut next = vec![0; healthy.len()];
+        let mut filled = 0;
+
+        'fill: while !healthy.is_empty() {
+            for (i, index) in healthy.iter().enumerate() {
+                let (offset, skip) = walks[i];
+
+                for _ in 0..hosts[*index].weight {
+                    // the next slot on this host's walk that is still free
+                    let mut slot = (offset + next[i] * skip) % size;
+                    while slots[slot].is_some() {
+                        next[i] += 1;
+                        slot = (offset + next[i] * skip) % size;
+                    }
+
+                    slots[slot] = Some(*index);
+                    next[i] += 1;
+                    filled += 1;
+
+                    if filled == size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+
+        Table {
+            hosts: hosts.iter().map(|host| (host.url.clone(), host.weight, host.healthy)).collect(),
+            slots: slots.into_iter().flatten().collect(),
+        }
+    }
+
+    fn built_for(&self, hosts: &[Host]) -> bool {
+        self.hosts.len() == hosts.len()
+            && self.hosts.iter().zip(hosts).all(|((url, weight, healthy), host)| {
+                *url == host.url && *weight == host.weight && *healthy == host.healthy
+            })
+    }
+
+    fn lookup(&self, key: u64) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+
+        Some(self.slots[(key % self.slots.len() as u64) as usize])
+    }
+}
+
+impl Maglev {
+    pub fn new(config: &config::Maglev) -> Maglev {
+        Maglev {
+            key: HashKey::parse(&config.key).expect("hash key is validated with the config"),
+            table_size: config.table_size,
+            table: ArcSwap::from_pointee(Table::default()),
+        }
+    }
+}
+
+impl Strategy for Maglev {
+    fn pick(&self, request: &Request, hosts: &[Host]) -> Option<usize> {
+        let mut table = self.table.load_full();
+
+        // the pool or its health changed since the last request, rebuild the
+        // table. requests racing to rebuild it all build the same one
+        if !table.built_for(hosts) {
+            table = Arc::new(Table::build(hosts, self.table_size));
+            self.table.store(table.clone());
+        }
+
+        table.lookup(hash(self.key.extract(request).as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tests::hosts;
+
+    const SIZE: usize = 65537;
+
+    fn shares(table: &Table, hosts: usize) -> Vec<usize> {
+        let mut shares = vec![0; hosts];
+        for index in &table.slots {
+            shares[*index] += 1;
+        }
+        shares
+    }
+
+    #[test]
+    fn fills_every_slot() {
+        let table = Table::build(&hosts(&[1, 1, 1]), SIZE);
+        assert_eq!(table.slots.len(), SIZE);
+    }
+
+    #[test]
+    fn spreads_evenly() {
+        let table = Table::build(&hosts(&[1; 10]), SIZE);
+        let shares = shares(&table, 10);
+
+        // every host owns either floor or ceil of SIZE / 10 slots
+        let min = shares.iter().min().unwrap();
+        let max = shares.iter().max().unwrap();
+        assert!(max - min <= 1, "uneven shares {:?}", shares);
+    }
+
+    #[test]
+    fn spreads_by_weight() {
+        let table = Table::build(&hosts(&[1, 2, 3]), SIZE);
+        let shares = shares(&table, 3);
+
+        for (i, share) in shares.iter().enumerate() {
+            let expected = SIZE * (i + 1) / 6;
+            assert!(share.abs_diff(expected) <= 3, "uneven shares {:?}", shares);
+        }
+    }
+
+    #[test]
+    fn skips_unhealthy_hosts() {
+        let mut pool = hosts(&[1, 1, 1]);
+        pool[1].healthy = false;
+
+        let table = Table::build(&pool, SIZE);
+        assert!(table.slots.iter().all(|index| *index != 1));
+
+        for host in pool.iter_mut() {
+            host.healthy = false;
+        }
+        assert_eq!(Table::build(&pool, SIZE).lookup(42), None);
+    }
+
+    #[test]
+    fn removing_a_host_moves_few_other_slots() {
+        let mut pool = hosts(&[1; 10]);
+        let before = Table::build(&pool, SIZE);
+
+        pool[3].healthy = false;
+        let after = Table::build(&pool, SIZE);
+
+        // slots of the removed host have to move, the others should mostly stay
+        let kept = before.slots.iter().filter(|index| **index != 3).count();
+        let moved = before.slots.iter().zip(&after.slots)
+            .filter(|(before, after)| **before != 3 && before != after)
+            .count();
+
+        assert!(moved * 100 < kept * 3, "{} of {} slots moved", moved, kept);
+    }
+
+    #[test]
+    fn adding_a_host_moves_about_its_share() {
+        let before = Table::build(&hosts(&[1; 10]), SIZE);
+        let after = Table::build(&hosts(&[1; 11]), SIZE);
+
+        // the new host takes about 1/11 of the table, plus a little churn
+        let moved = before.slots.iter().zip(&after.slots).filter(|(before, after)| before != after).count();
+        assert!(moved * 100 < SIZE * 12, "{} of {} slots moved", moved, SIZE);
+    }
+}
@@ -0,0 +1,163 @@
+use std::net::SocketAddr;
+
+use crate::config::Config;
+use crate::host::Host;
+use crate::http::Message;
+
+mod consistent_hash;
+mod least_connections;
+mod maglev;
+mod peak_ewma;
+mod rendezvous;
+mod round_robin;
+mod source_ip_hash;
+
+pub use consistent_hash::ConsistentHash;
+pub use least_connections::LeastConnections;
+pub use maglev::Maglev;
+pub use peak_ewma::PeakEwma;
+pub use rendezvous::Rendezvous;
+pub use round_robin::RoundRobin;
+pub use source_ip_hash::SourceIpHash;
+
+// what a strategy gets to know about the request being balanced
+pub struct Request<'a> {
+    // address of the client
+    pub peer: Option<SocketAddr>,
+    pub message: &'a Message,
+}
+
+// picks the backend a request is routed to
+//
+// `pick` is called with the current host list for every request, and again if
+// connecting to the picked host fails, after that host has been marked
+// unhealthy. strategies keep whatever state they need themselves.
+pub trait Strategy: Send + Sync {
+    // index into `hosts` of the host to route to, or None if no host can take
+    // the request
+    fn pick(&self, request: &Request, hosts: &[Host]) -> Option<usize>;
+}
+
+type Constructor = fn(&Config) -> Box<dyn Strategy>;
+
+// strategies selectable by name with the `strategy` config key, add your own here
+const STRATEGIES: &[(&str, Constructor)] = &[
+    ("round_robin", |_| Box::new(RoundRobin::default())),
+    ("least_connections", |_| Box::new(LeastConnections::default())),
+    ("consistent_hash", |config| Box::new(ConsistentHash::new(&config.consistent_hash))),
+    ("maglev", |config| Box::new(Maglev::new(&config.maglev))),
+    ("peak_ewma", |_| Box::new(PeakEwma)),
+    ("source_ip_hash", |_| Box::new(SourceIpHash)),
+    ("rendezvous", |config| Box::new(Rendezvous::new(&config.rendezvous))),
+];
+
+pub const DEFAULT: &str = "round_robin";
+
+pub fn names() -> Vec<&'static str> {
+    STRATEGIES.iter().map(|(name, _)| *name).collect()
+}
+
+pub fn exists(name: &str) -> bool {
+    STRATEGIES.iter().any(|(known, _)| *known == name)
+}
+
+// build the strategy named in the config, which has been validated already
+pub fn from_config(config: &Config) -> Box<dyn Strategy> {
+    let (_, constructor) = STRATEGIES.iter()
+        .find(|(name, _)| *name == config.strategy)
+        .expect("strategy is validated with the config");
+
+    constructor(config)
+}
+
+// the part of a request hashing strategies key on
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashKey {
+    ClientIp,
+    Path,
+    Header(String),
+}
+
+impl HashKey {
+    // parse `client_ip`, `path` or `header:<name>`
+    pub fn parse(key: &str) -> Option<HashKey> {
+        match key {
+            "client_ip" => Some(HashKey::ClientIp),
+            "path" => Some(HashKey::Path),
+            _ => match key.strip_prefix("header:") {
+                Some(name) if !name.is_empty() => Some(HashKey::Header(name.to_string())),
+                _ => None,
+            },
+        }
+    }
+
+    // the key of this request, falling back to the client ip when the request
+    // does not carry it
+    pub fn extract(&self, request: &Request) -> String {
+        let key = match self {
+            HashKey::ClientIp => None,
+            HashKey::Path => Some(request.message.path()),
+            HashKey::Header(name) => request.message.header(name),
+        };
+
+        match (key, request.peer) {
+            (Some(key), _) => key.to_string(),
+            (None, Some(peer)) => peer.ip().to_string(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+// 64 bit fnv-1a, finished with the murmur3 mixer so that similar inputs such as
+// "host-1" and "host-2" still land far apart
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+// fixtures shared by the tests of the strategies and of the host list
+#[cfg(test)]
+pub mod tests {
+    use std::time::Duration;
+
+    use crate::host::Host;
+    use crate::http::{Body, Message};
+
+    // healthy hosts at 10.0.0.<index>:8080 with these weights, all of priority 0
+    pub fn hosts(weights: &[u32]) -> Vec<Host> {
+        weights.iter().enumerate()
+            .map(|(i, weight)| Host {
+                healthy: true,
+                ..Host::new(&format!("10.0.0.{}:8080", i), *weight, 0, Duration::ZERO)
+            })
+            .collect()
+    }
+
+    // a GET request for `path` with these headers and no body
+    pub fn message(path: &str, headers: &[(&str, &str)]) -> Message {
+        let start_line = format!("GET {} HTTP/1.1", path);
+
+        let mut head = format!("{}\r\n", start_line);
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        Message {
+            head: head.into_bytes(),
+            start_line,
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body: Body::Length(0),
+        }
+    }
+}
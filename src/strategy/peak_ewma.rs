@@ -0,0 +1,86 @@
+use rand::Rng;
+
+use crate::host::Host;
+use crate::strategy::{Request, Strategy};
+
+// power of two choices over the peak ewma latency: pick two random healthy
+// hosts and route to the one with the lower expected cost, its latency times
+// the requests it is already serving, relative to its weight. slow or busy
+// hosts get less traffic without every request scanning the whole pool.
+#[derive(Default)]
+pub struct PeakEwma;
+
+impl PeakEwma {
+    fn cost(host: &Host) -> f64 {
+        host.latency.millis() * (host.active() + 1) as f64 / host.effective_weight()
+    }
+}
+
+impl Strategy for PeakEwma {
+    fn pick(&self, _request: &Request, hosts: &[Host]) -> Option<usize> {
+        let healthy: Vec<usize> = (0..hosts.len()).filter(|index| hosts[*index].healthy).collect();
+
+        if healthy.len() < 2 {
+            return healthy.first().copied();
+        }
+
+        // two distinct random hosts
+        let mut rng = rand::rng();
+        let first = rng.random_range(0..healthy.len());
+        let second = (first + rng.random_range(1..healthy.len())) % healthy.len();
+
+        let (first, second) = (healthy[first], healthy[second]);
+        if PeakEwma::cost(&hosts[second]) < PeakEwma::cost(&hosts[first]) {
+            Some(second)
+        } else {
+            Some(first)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::host::Connection;
+    use crate::strategy::tests::{hosts, message};
+
+    fn picks(hosts: &[Host], count: usize) -> Vec<Option<usize>> {
+        let message = message("/", &[]);
+        let request = Request { peer: None, message: &message };
+
+        (0..count).map(|_| PeakEwma.pick(&request, hosts)).collect()
+    }
+
+    #[test]
+    fn picks_the_cheaper_of_two() {
+        // with two hosts both are always the candidates, the faster one wins
+        let pool = hosts(&[1, 1]);
+        Connection::open(&pool[0]).observe(Duration::from_secs(1));
+        assert!(picks(&pool, 20).iter().all(|pick| *pick == Some(1)));
+
+        // until it is busy enough to cost more
+        let _busy: Vec<Connection> = (0..20).map(|_| Connection::open(&pool[1])).collect();
+        assert!(picks(&pool, 20).iter().all(|pick| *pick == Some(0)));
+
+        // weight lowers the cost
+        let pool = hosts(&[1, 4]);
+        let _busy: Vec<Connection> = (0..2).map(|_| Connection::open(&pool[1])).collect();
+        assert!(picks(&pool, 20).iter().all(|pick| *pick == Some(1)));
+    }
+
+    #[test]
+    fn never_picks_unhealthy_hosts() {
+        let mut pool = hosts(&[1, 1, 1, 1]);
+        pool[1].healthy = false;
+        pool[3].healthy = false;
+        assert!(picks(&pool, 100).iter().all(|pick| matches!(pick, Some(0) | Some(2))));
+
+        pool[0].healthy = false;
+        assert!(picks(&pool, 10).iter().all(|pick| *pick == Some(2)));
+
+        pool[2].healthy = false;
+        assert!(picks(&pool, 10).iter().all(Option::is_none));
+    }
+}
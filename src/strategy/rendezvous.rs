@@ -0,0 +1,120 @@
+use crate::config;
+use crate::host::Host;
+use crate::strategy::{hash, HashKey, Request, Strategy};
+
+// weighted rendezvous (highest random weight) hashing: every healthy host gets a
+// score from the hash of the request key and its url, and the highest score
+// wins. a key keeps going to the same host without any ring or table to
+// maintain, only the keys of a host that goes away move, and the scores are
+// skewed so that hosts win in proportion to their weights.
+pub struct Rendezvous {
+    key: HashKey,
+}
+
+impl Rendezvous {
+    pub fn new(config: &config::Rendezvous) -> Rendezvous {
+        Rendezvous {
+            key: HashKey::parse(&config.key).expect("hash key is validated with the config"),
+        }
+    }
+
+    // -weight / ln(u), with u uniform in (0, 1) from the hash of key and host
+    fn score(key: &str, host: &Host) -> f64 {
+        let h = hash(format!("{}#{}", key, host.url).as_bytes());
+        let u = ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        -host.effective_weight() / u.ln()
+    }
+}
+
+impl Strategy for Rendezvous {
+    fn pick(&self, request: &Request, hosts: &[Host]) -> Option<usize> {
+        let key = self.key.extract(request);
+
+        (0..hosts.len())
+            .filter(|index| hosts[*index].healthy)
+            .map(|index| (index, Rendezvous::score(&key, &hosts[index])))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::strategy::tests::{hosts, message};
+
+    const TENANTS: usize = 10000;
+
+    fn by_tenant() -> Rendezvous {
+        Rendezvous::new(&config::Rendezvous { key: "header:X-Tenant".to_string() })
+    }
+
+    fn pick(strategy: &Rendezvous, hosts: &[Host], tenant: &str) -> Option<usize> {
+        let message = message("/", &[("X-Tenant", tenant)]);
+        strategy.pick(&Request { peer: None, message: &message }, hosts)
+    }
+
+    // url of the host every tenant goes to
+    fn owners(hosts: &[Host]) -> Vec<Option<String>> {
+        let strategy = by_tenant();
+        (0..TENANTS)
+            .map(|tenant| pick(&strategy, hosts, &tenant.to_string()).map(|index| hosts[index].url.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn tenants_stick_to_their_host() {
+        let pool = hosts(&[1, 1, 1]);
+        assert_eq!(owners(&pool), owners(&pool));
+    }
+
+    #[test]
+    fn spreads_by_weight() {
+        let pool = hosts(&[1, 3]);
+        let light = owners(&pool).iter().filter(|owner| owner.as_deref() == Some(pool[0].url.as_str())).count();
+
+        assert!(light.abs_diff(TENANTS / 4) < TENANTS / 50, "{} of {} tenants on the light host", light, TENANTS);
+    }
+
+    #[test]
+    fn only_the_keys_of_a_host_that_goes_away_move() {
+        let pool = hosts(&[1; 5]);
+        let before = owners(&pool);
+        let gone = pool[2].url.clone();
+
+        let mut unhealthy = hosts(&[1; 5]);
+        unhealthy[2].healthy = false;
+        let removed: Vec<Host> = hosts(&[1; 5]).into_iter().filter(|host| host.url != gone).collect();
+
+        for after in [owners(&unhealthy), owners(&removed)] {
+            for (before, after) in before.iter().zip(&after) {
+                if before.as_deref() == Some(gone.as_str()) {
+                    assert_ne!(after, before);
+                } else {
+                    assert_eq!(after, before);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_client_ip() {
+        let pool = hosts(&[1; 5]);
+        let strategy = by_tenant();
+        let message = message("/", &[]);
+
+        let from = |ip: &str| {
+            let peer: SocketAddr = format!("{}:40000", ip).parse().unwrap();
+            strategy.pick(&Request { peer: Some(peer), message: &message }, &pool)
+        };
+        let by_ip = Rendezvous::new(&config::Rendezvous { key: "client_ip".to_string() });
+
+        for client in 0..100 {
+            let ip = format!("192.168.0.{}", client);
+            let peer: SocketAddr = format!("{}:40000", ip).parse().unwrap();
+            assert_eq!(from(&ip), by_ip.pick(&Request { peer: Some(peer), message: &message }, &pool));
+        }
+    }
+}
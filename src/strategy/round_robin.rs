@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use arc_swap::ArcSwap;
+
+use crate::host::Host;
+use crate::strategy::{Request, Strategy};
+
+// current weights are kept in thousandths, so slow start can ramp them smoothly
+const WEIGHT_SCALE: f64 = 1000.0;
+
+// smooth weighted round robin, as in nginx: every healthy host gains its weight,
+// the one with the highest current weight is picked and loses the total weight.
+// a host with weight 3 is picked three times as often as one with weight 1, and
+// the picks are interleaved rather than bursty.
+#[derive(Default)]
+pub struct RoundRobin {
+    // current weights, for the pool they were last built for
+    weights: ArcSwap<Weights>,
+}
+
+#[derive(Default)]
+struct Weights {
+    // urls of the hosts the weights were built for
+    hosts: Vec<String>,
+    // current weight of every host, by position in the pool. updated without a
+    // lock, concurrent picks may land on the same host now and then
+    current: Vec<AtomicI64>,
+}
+
+impl Weights {
+    // weights for `hosts`, carrying over those of the hosts still in the pool
+    fn build(hosts: &[Host], previous: &Weights) -> Weights {
+        let current = hosts.iter()
+            .map(|host| {
+                let kept = previous.hosts.iter().position(|url| *url == host.url);
+                AtomicI64::new(kept.map_or(0, |index| previous.current[index].load(Ordering::Relaxed)))
+            })
+            .collect();
+
+        Weights { hosts: hosts.iter().map(|host| host.url.clone()).collect(), current }
+    }
+
+    fn built_for(&self, hosts: &[Host]) -> bool {
+        self.hosts.len() == hosts.len() && self.hosts.iter().zip(hosts).all(|(url, host)| *url == host.url)
+    }
+}
+
+impl Strategy for RoundRobin {
+    fn pick(&self, _request: &Request, hosts: &[Host]) -> Option<usize> {
+        let mut weights = self.weights.load_full();
+
+        // the pool changed since the last request, rebuild the weights
+        if !weights.built_for(hosts) {
+            weights = Arc::new(Weights::build(hosts, &weights));
+            self.weights.store(weights.clone());
+        }
+
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+
+        for (index, host) in hosts.iter().enumerate() {
+            if !host.healthy {
+                continue;
+            }
+
+            let weight = (host.effective_weight() * WEIGHT_SCALE) as i64;
+            let current = weights.current[index].fetch_add(weight, Ordering::Relaxed) + weight;
+            total += weight;
+
+            if best.is_none_or(|(_, best)| current > best) {
+                best = Some((index, current));
+            }
+        }
+
+        let (best, _) = best?;
+        weights.current[best].fetch_sub(total, Ordering::Relaxed);
+        Some(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tests::{hosts, message};
+
+    fn picks(strategy: &RoundRobin, hosts: &[Host], count: usize) -> Vec<usize> {
+        let message = message("/", &[]);
+        let request = Request { peer: None, message: &message };
+
+        (0..count).map(|_| strategy.pick(&request, hosts).unwrap()).collect()
+    }
+
+    #[test]
+    fn interleaves_by_weight() {
+        let pool = hosts(&[3, 1]);
+        assert_eq!(picks(&RoundRobin::default(), &pool, 8), [0, 0, 1, 0, 0, 0, 1, 0]);
+
+        let pool = hosts(&[5, 1, 1]);
+        assert_eq!(picks(&RoundRobin::default(), &pool, 7), [0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn skips_unhealthy_hosts() {
+        let mut pool = hosts(&[1, 1, 1]);
+        pool[1].healthy = false;
+
+        assert_eq!(picks(&RoundRobin::default(), &pool, 4), [0, 2, 0, 2]);
+    }
+
+    #[test]
+    fn keeps_its_place_when_the_pool_changes() {
+        let strategy = RoundRobin::default();
+        let pool = hosts(&[3, 1]);
+        assert_eq!(picks(&strategy, &pool, 2), [0, 0]);
+
+        // a host added in front does not reset the others
+        let mut grown = hosts(&[1]);
+        grown[0].url = "10.0.0.9:8080".to_string();
+        grown.extend(pool);
+        assert_eq!(picks(&strategy, &grown, 5), [2, 1, 0, 1, 1]);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::host::Host;
+use crate::strategy::{hash, Request, Strategy};
+
+// how many times a client is rehashed before falling back to scanning the pool
+const REHASH_ATTEMPTS: u32 = 20;
+
+// hash the ip of the client onto the pool, in proportion to the weights, so a
+// client keeps landing on the same host without needing a cookie. when that
+// host is unhealthy the client is rehashed, as in nginx's ip_hash, so clients of
+// a failed host spread over the others but each always gets the same fallback.
+#[derive(Default)]
+pub struct SourceIpHash;
+
+impl Strategy for SourceIpHash {
+    fn pick(&self, request: &Request, hosts: &[Host]) -> Option<usize> {
+        let ip = request.peer.map(|peer| peer.ip().to_string()).unwrap_or_default();
+        let total: u64 = hosts.iter().map(|host| host.weight as u64).sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        for attempt in 0..REHASH_ATTEMPTS {
+            let mut point = hash(format!("{}#{}", ip, attempt).as_bytes()) % total;
+
+            // the host owning that point of the cumulative weights
+            let index = hosts.iter()
+                .position(|host| {
+                    if point < host.weight as u64 {
+                        return true;
+                    }
+                    point -= host.weight as u64;
+                    false
+                })
+                .expect("point is below the total weight");
+
+            if hosts[index].healthy {
+                return Some(index);
+            }
+        }
+
+        // unlucky, take the first healthy host after the original one
+        let start = (hash(format!("{}#0", ip).as_bytes()) % hosts.len() as u64) as usize;
+        (0..hosts.len())
+            .map(|offset| (start + offset) % hosts.len())
+            .find(|index| hosts[*index].healthy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::strategy::tests::{hosts, message};
+
+    fn pick(hosts: &[Host], client: usize) -> Option<usize> {
+        let message = message("/", &[]);
+        let peer: SocketAddr = format!("192.168.{}.{}:40000", client / 256, client % 256).parse().unwrap();
+
+        SourceIpHash.pick(&Request { peer: Some(peer), message: &message }, hosts)
+    }
+
+    #[test]
+    fn clients_stick_to_their_host() {
+        let pool = hosts(&[1, 2, 1]);
+        for client in 0..100 {
+            assert_eq!(pick(&pool, client), pick(&pool, client));
+        }
+    }
+
+    #[test]
+    fn clients_of_an_unhealthy_host_always_get_the_same_fallback() {
+        let mut pool = hosts(&[1; 5]);
+        let before: Vec<_> = (0..1000).map(|client| pick(&pool, client)).collect();
+
+        pool[2].healthy = false;
+        let mut fallbacks = vec![0; 5];
+        for (client, before) in before.iter().enumerate() {
+            let after = pick(&pool, client);
+            if *before == Some(2) {
+                // the same fallback every time, spread over the other hosts
+                let after = after.unwrap();
+                assert_ne!(after, 2);
+                assert_eq!(pick(&pool, client), Some(after));
+                fallbacks[after] += 1;
+            } else {
+                // clients of the healthy hosts do not move
+                assert_eq!(after, *before);
+            }
+        }
+        assert!(fallbacks.iter().enumerate().all(|(index, count)| index == 2 || *count > 0), "fallbacks {:?}", fallbacks);
+    }
+
+    #[test]
+    fn scans_the_pool_when_rehashing_keeps_missing() {
+        // hosts with no weight are never hashed to, so every rehash lands on an
+        // unhealthy host and the pool is scanned from where the client started
+        let mut pool = hosts(&[1, 0, 1, 0]);
+        pool[0].healthy = false;
+        pool[2].healthy = false;
+
+        for client in 0..100 {
+            let picked = pick(&pool, client);
+            assert!(matches!(picked, Some(1) | Some(3)));
+            assert_eq!(pick(&pool, client), picked);
+        }
+
+        pool[1].healthy = false;
+        pool[3].healthy = false;
+        assert_eq!(pick(&pool, 0), None);
+    }
+}